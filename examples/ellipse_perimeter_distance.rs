@@ -14,18 +14,10 @@ fn main() {
         p.0[0] = (255.0 * ((d / 5.).tanh())) as u8;
     });
 
-    let res = 50;
-    let ellipse_poly = (0..res)
-        .into_iter()
-        .map(|i| {
-            let angle = (i as f32 / res as f32) * 2.0 * std::f32::consts::PI;
-            let x = ellipse.a as f32 * angle.cos();
-            let y = ellipse.b as f32 * angle.sin();
-            let rotangle = ellipse.theta as f32;
-            let x_rot = x * rotangle.cos() - y * rotangle.sin();
-            let y_rot = x * rotangle.sin() + y * rotangle.cos();
-            Point::new(x_rot + ellipse.x as f32, y_rot + ellipse.y as f32)
-        })
+    let ellipse_poly = ellipse
+        .to_polygon(50)
+        .iter()
+        .map(|p| Point::new(p.x as f32, p.y as f32))
         .collect::<Vec<Point<f32>>>();
     draw_hollow_polygon_mut(&mut figure, &ellipse_poly[..], Luma([0u8]));
 
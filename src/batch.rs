@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::{
+    detect::{detect_ellipses, DetectOptions},
+    error::DetectError,
+    fit_args::FitArgs,
+    frames::Frame,
+    robust_fit::Detection,
+};
+
+/// Image file extensions picked up when listing a directory
+pub const IMAGE_EXTENSIONS: [&str; 6] = ["png", "bmp", "jpg", "jpeg", "tif", "tiff"];
+
+/// Outcome of processing a single frame of a batch
+#[derive(Debug)]
+pub struct FrameResult {
+    pub frame: Frame,
+    pub result: Result<Vec<Detection>, DetectError>,
+}
+
+/// Lists the input images of a batch in sorted order.
+/// If only `dir` is given, all images with a known extension in the directory are listed.
+/// If a glob `pattern` is given, it is matched relative to `dir` (when present).
+pub fn list_images(dir: Option<&Path>, pattern: Option<&str>) -> Result<Vec<PathBuf>, DetectError> {
+    let mut files = match (dir, pattern) {
+        (dir, Some(pattern)) => {
+            let pattern = match dir {
+                Some(dir) => dir.join(pattern).to_string_lossy().into_owned(),
+                None => pattern.to_string(),
+            };
+            glob::glob(&pattern)
+                .map_err(|e| DetectError::config(None, format!("invalid glob pattern: {}", e)))?
+                .map(|entry| {
+                    entry.map_err(|e| {
+                        DetectError::io(e.path().to_path_buf(), std::io::Error::from(e))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        (Some(dir), None) => std::fs::read_dir(dir)
+            .map_err(|e| DetectError::io(dir, e))?
+            .map(|entry| entry.map(|e| e.path()).map_err(|e| DetectError::io(dir, e)))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|p| {
+                p.extension()
+                    .and_then(|e| e.to_str())
+                    .map(|e| IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        (None, None) => vec![],
+    };
+    files.retain(|p| p.is_file());
+    files.sort();
+    Ok(files)
+}
+
+/// Runs the detection on each of the given frames. A failure on one frame does not
+/// stop the batch, it is reported in the result of that frame.
+/// If `options.multithread` is set, the frames are processed in parallel.
+pub fn detect_batch(
+    frames: &[Frame],
+    fit_args: &FitArgs,
+    options: &DetectOptions,
+) -> Vec<FrameResult> {
+    let process = |frame: &Frame| FrameResult {
+        frame: frame.clone(),
+        result: frame
+            .load()
+            .and_then(|img| detect_ellipses(&img, fit_args, options))
+            .map(|r| r.detections),
+    };
+    if options.multithread {
+        frames.par_iter().map(process).collect()
+    } else {
+        frames.iter().map(process).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_images_filters_and_sorts() {
+        let dir = std::env::temp_dir().join(format!("ellipse_detect_batch_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub.png")).unwrap();
+        for name in [
+            "c.png",
+            "a.PNG",
+            "b.tif",
+            "notes.txt",
+            "d.jpeg",
+            "frame_2.png",
+        ] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        let names = |files: Vec<PathBuf>| {
+            files
+                .iter()
+                .map(|f| f.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let all = list_images(Some(&dir), None);
+        let png = list_images(Some(&dir), Some("*.png"));
+        let absolute = list_images(None, Some(&dir.join("?.*").to_string_lossy()));
+        let invalid = list_images(Some(&dir), Some("[.png"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Directories and unknown extensions are left out, extensions are case insensitive
+        assert_eq!(
+            names(all.unwrap()),
+            vec!["a.PNG", "b.tif", "c.png", "d.jpeg", "frame_2.png"]
+        );
+        // The pattern is matched in the directory, and it is case sensitive
+        assert_eq!(names(png.unwrap()), vec!["c.png", "frame_2.png"]);
+        assert_eq!(
+            names(absolute.unwrap()),
+            vec!["a.PNG", "b.tif", "c.png", "d.jpeg"]
+        );
+        assert!(matches!(invalid, Err(DetectError::Config { .. })));
+        assert!(list_images(None, None).unwrap().is_empty());
+    }
+}
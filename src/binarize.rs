@@ -0,0 +1,402 @@
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Primitive};
+use imageproc::{
+    contrast::otsu_level,
+    filter::{box_filter, gaussian_blur_f32},
+};
+use serde::{Deserialize, Serialize};
+
+/// Grayscale image with the given value type
+pub type Gray<T> = ImageBuffer<Luma<T>, Vec<T>>;
+
+/// Method used for choosing the binarization threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinarizationMethod {
+    /// A single global threshold, given by the `threshold` config value
+    #[default]
+    Global,
+    /// A single global threshold, calculated with Otsu's method
+    Otsu,
+    /// Each pixel is compared to the mean of its neighbourhood
+    AdaptiveMean,
+    /// Each pixel is compared to the Gaussian weighted mean of its neighbourhood
+    AdaptiveGaussian,
+}
+
+/// Settings of the binarization step
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Binarization {
+    /// Method for choosing the threshold
+    #[serde(default)]
+    pub method: BinarizationMethod,
+
+    /// Radius of the neighbourhood used by the adaptive methods, in pixels
+    #[serde(default = "default_block_radius")]
+    pub block_radius: u32,
+
+    /// A pixel only counts as a particle pixel in the adaptive methods if it differs
+    /// from the local mean by more than this value. Given in 8-bit gray values,
+    /// scaled to the value range of other image types.
+    #[serde(default = "default_offset")]
+    pub offset: f64,
+
+    /// If false, the particles are expected to be darker than the background,
+    /// if true, the particles are expected to be brighter than the background
+    #[serde(default)]
+    pub invert: bool,
+}
+
+fn default_block_radius() -> u32 {
+    15
+}
+
+fn default_offset() -> f64 {
+    5.0
+}
+
+impl Default for Binarization {
+    fn default() -> Self {
+        Binarization {
+            method: BinarizationMethod::default(),
+            block_radius: default_block_radius(),
+            offset: default_offset(),
+            invert: false,
+        }
+    }
+}
+
+/// Value types of the grayscale images the binarization works with
+pub trait GrayValue: Primitive + Into<f64> + Send + Sync + 'static {
+    /// Name of the value type used in messages
+    const NAME: &'static str;
+
+    /// Value of a white pixel, the largest gray value of the type
+    const WHITE: f64;
+
+    /// Converts the image to grayscale with the default luma weights of the image crate
+    fn from_image(img: &DynamicImage) -> Gray<Self>;
+
+    /// Converts a gray value in the [0, 1] range to this type
+    fn from_unit(value: f32) -> Self;
+
+    /// Global threshold calculated with Otsu's method, in the [0, 1] range
+    fn otsu_level(img: &Gray<Self>) -> f32 {
+        histogram_otsu_level(&to_unit(img))
+    }
+
+    /// Mean of the square neighbourhood of the given radius of each pixel, in the [0, 1] range
+    fn box_mean(img: &Gray<Self>, radius: u32) -> Gray<f32> {
+        let horizontal = box_mean_rows(&to_unit(img), radius);
+        transpose(&box_mean_rows(&transpose(&horizontal), radius))
+    }
+
+    /// Gaussian weighted mean of the neighbourhood of each pixel, in the [0, 1] range
+    fn gaussian_mean(img: &Gray<Self>, sigma: f32) -> Gray<f32> {
+        let horizontal = gaussian_rows(&to_unit(img), sigma);
+        transpose(&gaussian_rows(&transpose(&horizontal), sigma))
+    }
+}
+
+impl GrayValue for u8 {
+    const NAME: &'static str = "8-bit";
+    const WHITE: f64 = u8::MAX as f64;
+
+    fn from_image(img: &DynamicImage) -> Gray<Self> {
+        img.to_luma8()
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value * 255.0).round().clamp(0.0, 255.0) as u8
+    }
+
+    // The 8-bit images use the filters of imageproc
+
+    fn otsu_level(img: &Gray<Self>) -> f32 {
+        otsu_level(img) as f32 / 255.0
+    }
+
+    fn box_mean(img: &Gray<Self>, radius: u32) -> Gray<f32> {
+        to_unit(&box_filter(img, radius, radius))
+    }
+
+    fn gaussian_mean(img: &Gray<Self>, sigma: f32) -> Gray<f32> {
+        to_unit(&gaussian_blur_f32(img, sigma))
+    }
+}
+
+impl GrayValue for u16 {
+    const NAME: &'static str = "16-bit";
+    const WHITE: f64 = u16::MAX as f64;
+
+    fn from_image(img: &DynamicImage) -> Gray<Self> {
+        img.to_luma16()
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value * 65535.0).round().clamp(0.0, 65535.0) as u16
+    }
+}
+
+impl GrayValue for f32 {
+    const NAME: &'static str = "floating point";
+    const WHITE: f64 = 1.0;
+
+    fn from_image(img: &DynamicImage) -> Gray<Self> {
+        img.to_luma32f()
+    }
+
+    fn from_unit(value: f32) -> Self {
+        value
+    }
+}
+
+/// Binarizes the image with the given settings. Background pixels are set to 255 and
+/// particle pixels to 0, regardless of the polarity of the input image.
+/// The `threshold` is only used by the global method, it is in the units of the image values.
+/// The values are compared in the [0, 1] range, so the same picture is binarized the same way
+/// whatever its value type is, apart from the rounding of the local means of 8-bit images.
+pub fn binarize<T: GrayValue>(img: &Gray<T>, settings: &Binarization, threshold: f64) -> GrayImage {
+    let unit = to_unit(img);
+    let invert = settings.invert;
+    let offset = (settings.offset / u8::MAX as f64) as f32;
+    let is_particle = move |p: f32, t: f32| {
+        if invert {
+            p > t
+        } else {
+            p <= t
+        }
+    };
+    let is_particle_local = move |p: f32, m: f32| {
+        if invert {
+            p > m + offset
+        } else {
+            p <= m - offset
+        }
+    };
+    let value = |x: u32, y: u32| unit.get_pixel(x, y)[0];
+    let compare_global = |level: f32| {
+        GrayImage::from_fn(img.width(), img.height(), |x, y| {
+            to_binary(is_particle(value(x, y), level))
+        })
+    };
+    let compare_local = |local: &Gray<f32>| {
+        GrayImage::from_fn(img.width(), img.height(), |x, y| {
+            to_binary(is_particle_local(value(x, y), local.get_pixel(x, y)[0]))
+        })
+    };
+    match settings.method {
+        BinarizationMethod::Global => {
+            // Scaled the same way as the image, so a pixel equal to the threshold stays equal
+            compare_global(threshold as f32 / T::WHITE as f32)
+        }
+        BinarizationMethod::Otsu => compare_global(T::otsu_level(img)),
+        BinarizationMethod::AdaptiveMean => compare_local(&T::box_mean(img, settings.block_radius)),
+        BinarizationMethod::AdaptiveGaussian => {
+            // Same sigma as OpenCV uses for a kernel of size 2 * block_radius + 1
+            let sigma = 0.3 * (settings.block_radius as f32 - 1.0) + 0.8;
+            compare_local(&T::gaussian_mean(img, sigma.max(0.1)))
+        }
+    }
+}
+
+fn to_binary(is_particle: bool) -> Luma<u8> {
+    if is_particle {
+        Luma([0u8])
+    } else {
+        Luma([255u8])
+    }
+}
+
+/// Scales the values of the image to the [0, 1] range. The division is rounded only once,
+/// so an 8-bit value and the same value in 16 bits give exactly the same result.
+fn to_unit<T: GrayValue>(img: &Gray<T>) -> Gray<f32> {
+    let white = T::WHITE as f32;
+    Gray::from_fn(img.width(), img.height(), |x, y| {
+        Luma([img.get_pixel(x, y)[0].into() as f32 / white])
+    })
+}
+
+fn transpose(img: &Gray<f32>) -> Gray<f32> {
+    Gray::from_fn(img.height(), img.width(), |x, y| *img.get_pixel(y, x))
+}
+
+/// Otsu's method on a histogram of the values between the smallest and largest value of the
+/// image, returns the upper end of the histogram bin of the threshold
+fn histogram_otsu_level(img: &Gray<f32>) -> f32 {
+    const BINS: usize = 4096;
+    let values = img.pixels().map(|p| p[0] as f64).collect::<Vec<f64>>();
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if max <= min {
+        return min as f32;
+    }
+    let bin_width = (max - min) / BINS as f64;
+    let mut hist = vec![0u64; BINS];
+    for v in values.iter() {
+        hist[(((v - min) / bin_width) as usize).min(BINS - 1)] += 1;
+    }
+
+    let total_weight = values.len() as u64;
+    let total_sum = hist
+        .iter()
+        .enumerate()
+        .fold(0f64, |sum, (t, &h)| sum + t as f64 * h as f64);
+    let mut background_sum = 0f64;
+    let mut background_weight = 0u64;
+    let mut largest_variance = 0f64;
+    let mut best_bin = 0;
+    for (t, &count) in hist.iter().enumerate() {
+        background_weight += count;
+        if background_weight == 0 {
+            continue;
+        }
+        let foreground_weight = total_weight - background_weight;
+        if foreground_weight == 0 {
+            break;
+        }
+        background_sum += t as f64 * count as f64;
+        let background_mean = background_sum / background_weight as f64;
+        let foreground_mean = (total_sum - background_sum) / foreground_weight as f64;
+        let variance = background_weight as f64
+            * foreground_weight as f64
+            * (background_mean - foreground_mean).powi(2);
+        if variance > largest_variance {
+            largest_variance = variance;
+            best_bin = t;
+        }
+    }
+    (min + (best_bin + 1) as f64 * bin_width) as f32
+}
+
+/// Mean of the 2 * radius + 1 wide window of each pixel along the rows,
+/// the pixels beyond the border take the value of the nearest border pixel
+fn box_mean_rows(img: &Gray<f32>, radius: u32) -> Gray<f32> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return img.clone();
+    }
+    let r = radius as i64;
+    let mut out = Gray::new(width, height);
+    let mut prefix = vec![0f64; (width as i64 + 2 * r + 1) as usize];
+    for y in 0..height {
+        for i in 0..(width as i64 + 2 * r) {
+            let x = (i - r).clamp(0, width as i64 - 1) as u32;
+            prefix[i as usize + 1] = prefix[i as usize] + img.get_pixel(x, y)[0] as f64;
+        }
+        for x in 0..width {
+            let sum = prefix[(x as i64 + 2 * r + 1) as usize] - prefix[x as usize];
+            out.put_pixel(x, y, Luma([(sum / (2 * r + 1) as f64) as f32]));
+        }
+    }
+    out
+}
+
+/// Gaussian weighted mean along the rows, the pixels beyond the border take the value
+/// of the nearest border pixel. The kernel is the one of imageproc's `gaussian_blur_f32`,
+/// which the 8-bit images are filtered with: the normal density up to 2 sigma, without
+/// normalizing the weights to a unit sum.
+fn gaussian_rows(img: &Gray<f32>, sigma: f32) -> Gray<f32> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return img.clone();
+    }
+    let r = (2.0 * sigma).ceil() as i64;
+    let kernel = (-r..=r)
+        .map(|i| {
+            let density = (-(i * i) as f32 / (2.0 * sigma * sigma)).exp();
+            density / ((2.0 * std::f32::consts::PI).sqrt() * sigma)
+        })
+        .collect::<Vec<_>>();
+    Gray::from_fn(width, height, |x, y| {
+        let sum = kernel.iter().enumerate().fold(0f32, |sum, (k, w)| {
+            let sx = (x as i64 + k as i64 - r).clamp(0, width as i64 - 1) as u32;
+            sum + w * img.get_pixel(sx, y)[0]
+        });
+        Luma([sum])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dark blobs on an unevenly lit background with some noise
+    fn test_image() -> GrayImage {
+        let rng = fastrand::Rng::with_seed(7);
+        GrayImage::from_fn(100, 100, |x, y| {
+            let background = 120.0 + x as f64 + 0.5 * y as f64;
+            let blob = [(30.0, 30.0), (70.0, 55.0), (40.0, 80.0)]
+                .iter()
+                .any(|(cx, cy)| (x as f64 - cx).hypot(y as f64 - cy) < 8.0);
+            let value = if blob { background - 60.0 } else { background };
+            Luma([(value + rng.f64() * 20.0 - 10.0).clamp(0.0, 255.0) as u8])
+        })
+    }
+
+    #[test]
+    fn value_types_binarize_the_same() {
+        let img = test_image();
+        let img16: Gray<u16> =
+            Gray::from_fn(100, 100, |x, y| Luma([img.get_pixel(x, y)[0] as u16 * 257]));
+        let img32: Gray<f32> = Gray::from_fn(100, 100, |x, y| {
+            Luma([img.get_pixel(x, y)[0] as f32 / 255.0])
+        });
+        for method in [
+            BinarizationMethod::Global,
+            BinarizationMethod::Otsu,
+            BinarizationMethod::AdaptiveMean,
+            BinarizationMethod::AdaptiveGaussian,
+        ] {
+            for invert in [false, true] {
+                let settings = Binarization {
+                    method,
+                    block_radius: 7,
+                    invert,
+                    ..Default::default()
+                };
+                let binary = binarize(&img, &settings, 150.0);
+                let particles = binary.pixels().filter(|p| p[0] == 0).count();
+                assert!(particles > 0 && particles < 10000, "{:?}", settings);
+                let binary16 = binarize(&img16, &settings, 150.0 * 257.0);
+                assert_eq!(binary16, binarize(&img32, &settings, 150.0 / 255.0));
+                if matches!(
+                    method,
+                    BinarizationMethod::Global | BinarizationMethod::Otsu
+                ) {
+                    assert_eq!(binary, binary16, "{:?}", settings);
+                } else {
+                    // imageproc rounds the local means of the 8-bit images down, which only moves the
+                    // pixels close to the local threshold
+                    let differences = binary
+                        .pixels()
+                        .zip(binary16.pixels())
+                        .filter(|(p8, p16)| p8 != p16)
+                        .count();
+                    assert!(differences < 300, "{:?}: {}", settings, differences);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_images_are_binarized() {
+        for method in [
+            BinarizationMethod::Global,
+            BinarizationMethod::Otsu,
+            BinarizationMethod::AdaptiveMean,
+            BinarizationMethod::AdaptiveGaussian,
+        ] {
+            let settings = Binarization {
+                method,
+                ..Default::default()
+            };
+            for (width, height) in [(0, 0), (0, 5), (5, 0)] {
+                let binary = binarize(&Gray::<u8>::new(width, height), &settings, 100.0);
+                assert_eq!(binary.dimensions(), (width, height));
+                let binary = binarize(&Gray::<u16>::new(width, height), &settings, 100.0);
+                assert_eq!(binary.dimensions(), (width, height));
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+use crate::robust_fit::Ellipse;
+
+/// Size of the image pixels in physical units (e.g. µm), for converting pixel measures
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Calibration {
+    /// Physical size of a pixel along the x axis, e.g. µm per pixel
+    pub pixel_width: f64,
+
+    /// Physical size of a pixel along the y axis, equal to `pixel_width` if not given
+    #[serde(default)]
+    pub pixel_height: Option<f64>,
+}
+
+/// Units of the length limits of the filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthUnit {
+    /// Pixels of the image
+    #[default]
+    Pixel,
+    /// Physical units of the calibration
+    Physical,
+}
+
+impl Calibration {
+    /// Scales of the x and y axes
+    pub fn scales(&self) -> (f64, f64) {
+        (
+            self.pixel_width,
+            self.pixel_height.unwrap_or(self.pixel_width),
+        )
+    }
+
+    /// Converts an ellipse given in pixel coordinates to physical coordinates
+    pub fn to_physical(&self, ellipse: &Ellipse) -> Option<Ellipse> {
+        let (sx, sy) = self.scales();
+        ellipse.scaled(sx, sy)
+    }
+
+    /// Physical length of a displacement given in pixels
+    pub fn length(&self, dx: f64, dy: f64) -> f64 {
+        let (sx, sy) = self.scales();
+        (dx * sx).hypot(dy * sy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fit_args::FitArgs, robust_fit::robust_fit_ellipse, test_util::ellipse_points};
+
+    #[test]
+    fn anisotropic_pixels_change_the_axes_and_orientation() {
+        let calibration = Calibration {
+            pixel_width: 2.0,
+            pixel_height: Some(1.0),
+        };
+        let ellipse = Ellipse::new(10.0, 20.0, 10.0, 5.0, std::f64::consts::FRAC_PI_4);
+        let physical = calibration.to_physical(&ellipse).unwrap();
+        // The axes are the square roots of the eigenvalues of M M^T, where
+        // M = diag(2, 1) R(pi / 4) diag(10, 5) maps the unit circle to the physical ellipse:
+        // M M^T = [[250, 75], [75, 62.5]], with the eigenvalues 156.25 +- sqrt(14414.0625)
+        assert!((physical.x - 20.0).abs() < 1e-9);
+        assert!((physical.y - 20.0).abs() < 1e-9);
+        assert!(
+            (physical.a - 16.622532281570876).abs() < 1e-9,
+            "{:?}",
+            physical
+        );
+        assert!(
+            (physical.b - 6.015930563772665).abs() < 1e-9,
+            "{:?}",
+            physical
+        );
+        assert!(
+            (physical.theta - 0.3373704711117763).abs() < 1e-9,
+            "{:?}",
+            physical
+        );
+        // The area is scaled by the area of a pixel
+        assert!((physical.a * physical.b - 2.0 * ellipse.a * ellipse.b).abs() < 1e-9);
+    }
+
+    #[test]
+    fn size_filters_apply_in_physical_units() {
+        let ellipse = Ellipse::new(100.0, 100.0, 30.0, 10.0, 0.3);
+        let contour = ellipse_points(&ellipse, 200, 0.0, &fastrand::Rng::with_seed(0));
+        let detect = |filter_units: &str, min_length: f64, max_length: f64| {
+            let fit_args: FitArgs = serde_yaml::from_str(&format!(
+                "threshold: 35
+min_fitness: 0.3
+dist_threshold: 2.0
+radius_threshold: 6.0
+min_contour_points: 0
+max_contour_points: 100000
+min_aspect_ratio: 0.0
+max_aspect_ratio: 1000.0
+min_length: {}
+max_length: {}
+min_width: 0.0
+max_width: 100000.0
+rotation_center_x: 0.0
+rotation_center_y: 0.0
+detect_radius_min: 0.0
+detect_radius_max: 100000.0
+calibration:
+  pixel_width: 2.0
+filter_units: {}
+",
+                min_length, max_length, filter_units
+            ))
+            .unwrap();
+            let rng = fastrand::Rng::with_seed(5);
+            robust_fit_ellipse(&contour, &fit_args, 10.0, &rng)
+                .unwrap()
+                .len()
+        };
+        // The particle is 60 pixels long, 120 in physical units
+        assert_eq!(detect("pixel", 50.0, 70.0), 1);
+        assert_eq!(detect("pixel", 100.0, 130.0), 0);
+        assert_eq!(detect("physical", 50.0, 70.0), 0);
+        assert_eq!(detect("physical", 100.0, 130.0), 1);
+    }
+}
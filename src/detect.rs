@@ -0,0 +1,243 @@
+use std::path::Path;
+
+use image::{io::Reader as ImageReader, DynamicImage, ImageError};
+use imageproc::{
+    contours::{find_contours, Contour},
+    point::Point,
+};
+use rayon::prelude::*;
+
+use crate::{
+    binarize::{binarize, BinarizationMethod, Gray, GrayValue},
+    error::DetectError,
+    fit_args::FitArgs,
+    grayscale::GrayDepth,
+    robust_fit::{robust_fit_ellipse, Detection},
+    subpixel::EdgeRefiner,
+};
+
+/// Options of the detection pipeline that are not read from the config file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectOptions {
+    /// Multiplier for the number of random samples to take
+    pub samplemult: f64,
+
+    /// If true, the contours are fitted in parallel on all available cores
+    pub multithread: bool,
+}
+
+impl Default for DetectOptions {
+    fn default() -> Self {
+        DetectOptions {
+            samplemult: 10.0,
+            multithread: false,
+        }
+    }
+}
+
+/// Output of the detection pipeline, along with the intermediate contours
+#[derive(Debug, Clone, Default)]
+pub struct DetectionResult {
+    /// The contours that passed the contour filters, in pixel coordinates
+    pub contours: Vec<Vec<Point<f64>>>,
+
+    /// The ellipses fitted to the contours, along with their quality metrics
+    pub detections: Vec<Detection>,
+}
+
+/// Runs the full detection pipeline on the given image:
+/// grayscale conversion, binarization, contour detection, contour filtering and robust
+/// ellipse fitting.
+pub fn detect_ellipses(
+    img: &DynamicImage,
+    fit_args: &FitArgs,
+    options: &DetectOptions,
+) -> Result<DetectionResult, DetectError> {
+    if !fit_args.roi.is_loaded() {
+        return Err(DetectError::config(
+            None,
+            "roi.mask is given, but the mask image is not loaded",
+        ));
+    }
+    fit_args.roi.check_size(img.width(), img.height())?;
+    let grayscale = &fit_args.grayscale;
+    let contours = match grayscale.depth_for(img) {
+        GrayDepth::Auto | GrayDepth::U8 => {
+            find_eligible_contours(&grayscale.convert::<u8>(img), fit_args)?
+        }
+        GrayDepth::U16 => find_eligible_contours(&grayscale.convert::<u16>(img), fit_args)?,
+        GrayDepth::F32 => find_eligible_contours(&grayscale.convert::<f32>(img), fit_args)?,
+    };
+    let detections = fit_contours(&contours, fit_args, options)?;
+    Ok(DetectionResult {
+        contours,
+        detections,
+    })
+}
+
+/// Binarizes the image with the configured binarization settings and returns the contours
+/// that satisfy the contour point count filters.
+/// If `subpixel_edges` is set, the contour points are refined to sub-pixel edge positions.
+/// Fails if the global threshold is out of the range of the gray values.
+pub fn find_eligible_contours<T: GrayValue>(
+    img: &Gray<T>,
+    fit_args: &FitArgs,
+) -> Result<Vec<Vec<Point<f64>>>, DetectError> {
+    let global = fit_args.binarization.method == BinarizationMethod::Global;
+    if global && !(0.0..=T::WHITE).contains(&fit_args.threshold) {
+        return Err(DetectError::config(
+            None,
+            format!(
+                "threshold {} is out of the range of {} gray values, from 0 to {}",
+                fit_args.threshold,
+                T::NAME,
+                T::WHITE
+            ),
+        ));
+    }
+    let binary = binarize(img, &fit_args.binarization, fit_args.threshold);
+    let contours = find_contours(&binary)
+        .into_iter()
+        .filter(|c| {
+            c.points.len() >= fit_args.min_contour_points
+                && c.points.len() <= fit_args.max_contour_points
+        })
+        .map(|c: Contour<i32>| {
+            c.points
+                .iter()
+                .map(|p| Point::new(p.x.into(), p.y.into()))
+                .collect::<Vec<_>>()
+        });
+    if fit_args.subpixel_edges {
+        let refiner = EdgeRefiner::new(img);
+        Ok(contours.map(|c| refiner.refine(&c)).collect())
+    } else {
+        Ok(contours.collect())
+    }
+}
+
+/// Fits ellipses to each of the given contours using the robust RANSAC based method.
+/// Every contour gets its own random generator, seeded from `fit_args.seed` and the index
+/// of the contour, so the results do not depend on whether multithreading is used.
+pub fn fit_contours(
+    contours: &[Vec<Point<f64>>],
+    fit_args: &FitArgs,
+    options: &DetectOptions,
+) -> Result<Vec<Detection>, DetectError> {
+    let base_seed = fit_args.seed.unwrap_or_else(|| fastrand::u64(..));
+    let fit = |(i, ps): (usize, &Vec<Point<f64>>)| -> Result<Vec<Detection>, DetectError> {
+        let rng = fastrand::Rng::with_seed(contour_seed(base_seed, i));
+        let mut detections = robust_fit_ellipse(ps, fit_args, options.samplemult, &rng)?;
+        detections.iter_mut().for_each(|d| d.contour_id = i);
+        Ok(detections)
+    };
+    let fits: Vec<Vec<Detection>> = if options.multithread {
+        contours
+            .par_iter()
+            .enumerate()
+            .map(fit)
+            .collect::<Result<_, _>>()?
+    } else {
+        contours
+            .iter()
+            .enumerate()
+            .map(fit)
+            .collect::<Result<_, _>>()?
+    };
+    Ok(fits.into_iter().flatten().collect())
+}
+
+/// Derives the seed of the contour with the given index from the base seed (SplitMix64 step)
+fn contour_seed(base_seed: u64, index: usize) -> u64 {
+    let mut z = base_seed.wrapping_add((index as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Opens and decodes the image at the given path
+pub fn load_image(path: impl AsRef<Path>) -> Result<DynamicImage, DetectError> {
+    let path = path.as_ref();
+    ImageReader::open(path)
+        .map_err(|e| DetectError::io(path, e))?
+        .decode()
+        .map_err(|e| image_error(path, e))
+}
+
+/// Saves the image to the given path, the format is deduced from the extension
+pub fn save_image(img: &DynamicImage, path: impl AsRef<Path>) -> Result<(), DetectError> {
+    let path = path.as_ref();
+    img.save(path).map_err(|e| image_error(path, e))
+}
+
+pub(crate) fn image_error(path: &Path, e: ImageError) -> DetectError {
+    match e {
+        ImageError::IoError(e) => DetectError::io(path, e),
+        e => DetectError::Decode {
+            path: path.to_path_buf(),
+            source: e,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{robust_fit::Ellipse, test_util::ellipse_points};
+
+    /// Perimeter points of a few ellipses, with some noise and outliers
+    fn test_contours() -> Vec<Vec<Point<f64>>> {
+        let rng = fastrand::Rng::with_seed(3);
+        [
+            Ellipse::new(100.0, 80.0, 40.0, 12.0, 0.4),
+            Ellipse::new(300.0, 200.0, 35.0, 10.0, 2.0),
+            Ellipse::new(50.0, 250.0, 45.0, 15.0, 1.2),
+        ]
+        .iter()
+        .map(|e| {
+            let mut points = ellipse_points(e, 200, 0.5, &rng);
+            points.extend((0..20).map(|_| Point::new(e.x + rng.f64() * 30.0, e.y)));
+            points
+        })
+        .collect()
+    }
+
+    #[test]
+    fn fit_contours_is_deterministic_with_seed() {
+        let contours = test_contours();
+        let fit_args: FitArgs = serde_yaml::from_str(
+            "threshold: 35
+min_fitness: 0.3
+dist_threshold: 2.0
+radius_threshold: 6.0
+min_contour_points: 0
+max_contour_points: 100000
+min_aspect_ratio: 0.0
+max_aspect_ratio: 1000.0
+min_length: 0.0
+max_length: 100000.0
+min_width: 0.0
+max_width: 100000.0
+rotation_center_x: 0.0
+rotation_center_y: 0.0
+detect_radius_min: 0.0
+detect_radius_max: 100000.0
+seed: 42
+",
+        )
+        .unwrap();
+        let fit = |multithread| {
+            let options = DetectOptions {
+                multithread,
+                ..Default::default()
+            };
+            fit_contours(&contours, &fit_args, &options).unwrap()
+        };
+        let single = fit(false);
+        assert_eq!(single.len(), 3);
+        assert_eq!(single, fit(false));
+        assert_eq!(single, fit(true));
+        let contour_ids = single.iter().map(|d| d.contour_id).collect::<Vec<_>>();
+        assert_eq!(contour_ids, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,16 @@
+use image::{DynamicImage, Rgba};
+use imageproc::{drawing::draw_hollow_polygon_mut, point::Point};
+
+use crate::robust_fit::Ellipse;
+
+/// Draws the outlines of the given ellipses onto the image, each approximated by a polygon
+pub fn draw_ellipses_mut(img: &mut DynamicImage, ellipses: &[Ellipse], color: Rgba<u8>) {
+    for ellipse in ellipses.iter() {
+        let ellipse_poly = ellipse
+            .to_polygon(40)
+            .iter()
+            .map(|p| Point::new(p.x as f32, p.y as f32))
+            .collect::<Vec<Point<f32>>>();
+        draw_hollow_polygon_mut(img, &ellipse_poly[..], color);
+    }
+}
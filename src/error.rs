@@ -0,0 +1,94 @@
+use std::{fmt, path::PathBuf};
+
+/// Errors that can occur while loading the inputs, detecting ellipses or writing the outputs
+#[derive(Debug)]
+pub enum DetectError {
+    /// Failed to read or write a file
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// The config file is malformed or contains invalid values
+    Config {
+        path: Option<PathBuf>,
+        message: String,
+    },
+
+    /// Failed to decode or encode an image
+    Decode {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+
+    /// The input is not suitable for fitting (e.g. an empty contour)
+    DegenerateInput(String),
+
+    /// A numerical computation produced an unusable result
+    Numerical(String),
+
+    /// Some of the images of a batch could not be processed
+    BatchFailures { failed: usize, total: usize },
+}
+
+impl DetectError {
+    /// Process exit code used by the binary for this kind of error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DetectError::Io { .. } => 2,
+            DetectError::Config { .. } => 3,
+            DetectError::Decode { .. } => 4,
+            DetectError::DegenerateInput(_) => 5,
+            DetectError::Numerical(_) => 6,
+            DetectError::BatchFailures { .. } => 7,
+        }
+    }
+
+    pub(crate) fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        DetectError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn config(path: Option<PathBuf>, message: impl fmt::Display) -> Self {
+        DetectError::Config {
+            path,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DetectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetectError::Io { path, source } => write!(f, "I/O error on {:?}: {}", path, source),
+            DetectError::Config {
+                path: Some(path),
+                message,
+            } => write!(f, "Invalid config file {:?}: {}", path, message),
+            DetectError::Config {
+                path: None,
+                message,
+            } => write!(f, "Invalid config: {}", message),
+            DetectError::Decode { path, source } => {
+                write!(f, "Failed to process image {:?}: {}", path, source)
+            }
+            DetectError::DegenerateInput(message) => write!(f, "Degenerate input: {}", message),
+            DetectError::Numerical(message) => write!(f, "Numerical failure: {}", message),
+            DetectError::BatchFailures { failed, total } => {
+                write!(f, "Failed to process {} of {} images", failed, total)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DetectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DetectError::Io { source, .. } => Some(source),
+            DetectError::Decode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -1,26 +1,72 @@
 use clap::Parser;
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    binarize::Binarization,
+    calibration::{Calibration, LengthUnit},
+    error::DetectError,
+    fit_ellipse::FitMethod,
+    grayscale::Grayscale,
+    output::OutputFormat,
+    robust_fit::{PerimeterApprox, Refinement},
+    roi::{Region, Roi},
+    tracking::TrackingOptions,
+};
 
 // Program to detect elongated particles on images
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct CliArgs {
     /// Pathname of the image to open
-    #[clap(short, long, value_parser)]
-    pub file: PathBuf,
+    #[clap(short, long, value_parser, required_unless_present_any = &["input-dir", "glob", "stack", "pattern", "print-config"])]
+    pub file: Option<PathBuf>,
+
+    /// Directory of images to process in batch mode.
+    /// All png, bmp, jpg and tif images in the directory are processed,
+    /// unless a glob pattern is given, which is then matched inside this directory.
+    #[clap(long, value_parser, conflicts_with = "file")]
+    pub input_dir: Option<PathBuf>,
+
+    /// Glob pattern of the images to process in batch mode, e.g. "frames/*.png"
+    #[clap(long, value_parser, conflicts_with = "file")]
+    pub glob: Option<String>,
+
+    /// Multi-page TIFF file to process in batch mode, each page is a frame
+    #[clap(long, value_parser, conflicts_with_all = &["file", "input-dir", "glob", "pattern"])]
+    pub stack: Option<PathBuf>,
+
+    /// Pattern of numbered frames to process in batch mode, e.g. "frames/frame_%05d.png".
+    /// The frames are read in order from the first existing number among 0 to 4 until a number is missing.
+    #[clap(long, value_parser, conflicts_with_all = &["file", "input-dir", "glob"])]
+    pub pattern: Option<String>,
+
+    /// Directory for the output files in batch mode, one file is written per image.
+    /// Only available in batch mode.
+    #[clap(long, value_parser, conflicts_with = "file")]
+    pub outdir: Option<PathBuf>,
 
     /// Pathname of the config file for filtering
     #[clap(short, long, value_parser)]
     pub config: PathBuf,
 
-    /// Pathname for the output JSON file
-    /// If not specified, the output file is omitted
+    /// Pathname for the output file
+    /// If not specified, the output file is omitted.
+    /// In batch mode, the results of all images are combined into this file.
     #[clap(long, value_parser)]
     pub outfile: Option<PathBuf>,
 
+    /// Format of the output files
+    #[clap(long, value_enum, default_value = "json")]
+    pub format: OutputFormat,
+
     /// Pathname for the output image file
-    /// If not specified, the output image is omitted
+    /// If not specified, the output image is omitted. Not available in batch mode.
     #[clap(long, value_parser)]
     pub outimg: Option<PathBuf>,
 
@@ -37,12 +83,127 @@ pub struct CliArgs {
     /// If used, the program will utilize all available cores, otherwise it will use only one core.
     #[clap(long, parse(from_occurrences))]
     pub multithread: usize,
+
+    /// Seed for the random sampling, overrides the seed in the config file.
+    /// If neither is given, the results differ from run to run.
+    #[clap(long, value_parser)]
+    pub seed: Option<u64>,
+
+    /// Overrides a value of the config file, e.g. --set threshold=40.
+    /// Nested keys are separated by dots, e.g. --set refit.fitter=taubin.
+    /// Can be given multiple times, these take precedence over the environment variables.
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<ConfigOverride>,
+
+    /// Pathname for the trajectories of the particles tracked across the images of a batch.
+    /// If not specified, the particles are not tracked. Only available in batch mode.
+    #[clap(long, value_parser, conflicts_with = "file")]
+    pub tracks: Option<PathBuf>,
+
+    /// Prints the effective config, after applying the overrides, as YAML and exits
+    #[clap(long)]
+    pub print_config: bool,
+}
+
+/// Prefix of the environment variables overriding the values of the config file,
+/// e.g. ELLIPSE_DETECT_THRESHOLD=40 or ELLIPSE_DETECT_REFIT__FITTER=taubin
+pub const ENV_PREFIX: &str = "ELLIPSE_DETECT_";
+
+/// Where a config override comes from, named in the error messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverrideSource {
+    /// The environment variable with the given name
+    Env(String),
+    /// The `--set` command line option
+    CommandLine,
+}
+
+impl fmt::Display for OverrideSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideSource::Env(name) => write!(f, "environment variable {}", name),
+            OverrideSource::CommandLine => write!(f, "--set"),
+        }
+    }
+}
+
+/// Replaces the value of a config key, the value is parsed as YAML
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigOverride {
+    /// Dot separated path of the key, e.g. refit.fitter
+    pub key: String,
+    pub value: String,
+    pub source: OverrideSource,
+}
+
+/// Parses a KEY=VALUE config override given on the command line
+fn parse_override(s: &str) -> Result<ConfigOverride, String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok(ConfigOverride {
+            key: key.trim().to_string(),
+            value: value.to_string(),
+            source: OverrideSource::CommandLine,
+        }),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+/// Collects the config overrides from the environment variables starting with `ENV_PREFIX`.
+/// The rest of the variable name is lowercased to get the key, with `__` separating nested keys.
+/// Fails if the value of such a variable is not valid UTF-8.
+pub fn env_overrides() -> Result<Vec<ConfigOverride>, DetectError> {
+    overrides_from_vars(std::env::vars_os())
+}
+
+/// Config overrides of the given environment variables, sorted by their names.
+/// Variables with names that are not valid UTF-8 are skipped, since they cannot match the prefix.
+/// Fails if the value of a matching variable is not valid UTF-8.
+fn overrides_from_vars(
+    vars: impl Iterator<Item = (OsString, OsString)>,
+) -> Result<Vec<ConfigOverride>, DetectError> {
+    let mut vars = vars
+        .filter_map(|(name, value)| {
+            let name = name.into_string().ok()?;
+            name.starts_with(ENV_PREFIX).then_some((name, value))
+        })
+        .collect::<Vec<_>>();
+    vars.sort();
+    vars.into_iter()
+        .map(|(name, value)| match value.into_string() {
+            Ok(value) => Ok(ConfigOverride {
+                key: name[ENV_PREFIX.len()..].to_lowercase().replace("__", "."),
+                value,
+                source: OverrideSource::Env(name),
+            }),
+            Err(_) => Err(DetectError::config(
+                None,
+                format!(
+                    "the value of the environment variable {} is not valid UTF-8",
+                    name
+                ),
+            )),
+        })
+        .collect()
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct FitArgs {
-    /// Threshold for binarization
-    pub threshold: u8,
+    /// Threshold for binarization, in the units of the grayscale image
+    /// (0-255 for 8-bit, 0-65535 for 16-bit and 0-1 for floating point images)
+    pub threshold: f64,
+
+    /// Binarization settings, by default the global `threshold` is used
+    #[serde(default)]
+    pub binarization: Binarization,
+
+    /// Conversion of the input images to grayscale
+    #[serde(default)]
+    pub grayscale: Grayscale,
+
+    /// If true, the contour pixels are refined to sub-pixel edge positions before fitting
+    #[serde(default)]
+    pub subpixel_edges: bool,
 
     /// Minimum fitness value for ellipse fitting
     pub min_fitness: f64,
@@ -53,39 +214,635 @@ pub struct FitArgs {
     /// Radius threshold for random sampling (in pixel units)
     pub radius_threshold: f64,
 
+    /// Pixel size for reporting the ellipses in physical units as well
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+
+    /// Units of the length, width and detection radius limits, physical units require a calibration
+    #[serde(default)]
+    pub filter_units: LengthUnit,
+
     /// Minimum number of points in a contour
+    #[serde(default)]
     pub min_contour_points: usize,
 
     /// Maximum number of points in a contour
+    #[serde(default = "default_max_count")]
     pub max_contour_points: usize,
 
     /// Minimum value of particle aspect ratio (must be >= 1.0, since the aspect ratio is always calculated to be >= 1.0)
+    #[serde(default = "default_min_aspect_ratio")]
     pub min_aspect_ratio: f64,
 
     /// Maximum value of particle aspect ratio (must be >= 1.0, since the aspect ratio is always calculated to be >= 1.0)
+    #[serde(default = "default_max_value")]
     pub max_aspect_ratio: f64,
 
-    /// Minimum length of a particle, in pixels
+    /// Minimum length of a particle, in `filter_units`
+    #[serde(default)]
     pub min_length: f64,
 
-    /// Maximum length of a particle, in pixels
+    /// Maximum length of a particle, in `filter_units`
+    #[serde(default = "default_max_value")]
     pub max_length: f64,
 
-    /// Minimum width of a particle, in pixels
+    /// Minimum width of a particle, in `filter_units`
+    #[serde(default)]
     pub min_width: f64,
 
-    /// Maximum width of a particle, in pixels
+    /// Maximum width of a particle, in `filter_units`
+    #[serde(default = "default_max_value")]
     pub max_width: f64,
 
-    /// Center of rotation, x coordinate
+    /// Center of rotation, x coordinate (in pixels)
+    #[serde(default)]
     pub rotation_center_x: f64,
 
-    /// Center of rotation, y coordinate
+    /// Center of rotation, y coordinate (in pixels)
+    #[serde(default)]
     pub rotation_center_y: f64,
 
-    /// Minimum detection radius measured from the center of rotation
+    /// Region of interest, the whole image by default
+    #[serde(default)]
+    pub roi: Roi,
+
+    /// If true, the position and orientation of the ellipses relative to the center of rotation are reported as well
+    #[serde(default)]
+    pub polar: bool,
+
+    /// Minimum detection radius measured from the center of rotation, in `filter_units`
+    #[serde(default)]
     pub detect_radius_min: f64,
 
-    /// Maximum detection radius measured from the center of rotation
+    /// Maximum detection radius measured from the center of rotation, in `filter_units`
+    #[serde(default = "default_max_value")]
     pub detect_radius_max: f64,
+
+    /// Method used for fitting the ellipses to the random samples
+    #[serde(default)]
+    pub fitter: FitMethod,
+
+    /// If given, the best ellipse of each RANSAC round is refitted on all of its inliers
+    #[serde(default)]
+    pub refit: Option<Refinement>,
+
+    /// Approximation of the ellipse perimeter used in the fitness score
+    #[serde(default)]
+    pub perimeter: PerimeterApprox,
+
+    /// Settings of the tracking of the particles across the images of a batch
+    #[serde(default)]
+    pub tracking: TrackingOptions,
+
+    /// Seed for the random sampling, if not given, a random seed is used
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Probability of drawing at least one sample free of outliers in each RANSAC round
+    #[serde(default = "default_confidence")]
+    pub confidence: f64,
+
+    /// Maximum number of samples drawn in each RANSAC round
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,
+
+    /// The RANSAC rounds on a contour stop when fewer points remain on it, at least 5
+    #[serde(default = "default_min_remaining_points")]
+    pub min_remaining_points: usize,
+
+    /// Number of point pairs (along with their neighbourhoods) in a random sample
+    #[serde(default = "default_sample_pairs")]
+    pub sample_pairs: usize,
+
+    /// Minimum distance of the points of a sample pair, in units of `radius_threshold`
+    #[serde(default = "default_pair_distance_min")]
+    pub pair_distance_min: f64,
+
+    /// Maximum distance of the points of a sample pair, in units of `radius_threshold`
+    #[serde(default = "default_pair_distance_max")]
+    pub pair_distance_max: f64,
+
+    /// Number of tries for drawing the pairs of a sample, after which pairs are accepted regardless of their distance
+    #[serde(default = "default_max_pair_tries")]
+    pub max_pair_tries: usize,
+
+    /// Number of iterations when estimating the distance of the contour points from an ellipse
+    #[serde(default = "default_distance_iterations")]
+    pub distance_iterations: usize,
+}
+
+// The filters are disabled by default, their lower limits default to the smallest
+// and their upper limits to the largest possible value
+fn default_max_count() -> usize {
+    usize::MAX
+}
+
+fn default_min_aspect_ratio() -> f64 {
+    1.0
+}
+
+fn default_max_value() -> f64 {
+    f64::INFINITY
+}
+
+fn default_confidence() -> f64 {
+    0.99
+}
+
+fn default_max_iterations() -> usize {
+    10000
+}
+
+fn default_min_remaining_points() -> usize {
+    30
+}
+
+fn default_sample_pairs() -> usize {
+    3
+}
+
+fn default_pair_distance_min() -> f64 {
+    2.0
+}
+
+fn default_pair_distance_max() -> f64 {
+    10.0
+}
+
+fn default_max_pair_tries() -> usize {
+    10000
+}
+
+fn default_distance_iterations() -> usize {
+    3
+}
+
+impl FitArgs {
+    /// Reads and parses the YAML config file at the given path
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, DetectError> {
+        Self::from_file_with_overrides(path, &[])
+    }
+
+    /// Reads and parses the YAML config file at the given path, replacing the values of the
+    /// given keys. The overrides are applied in order, so the later ones take precedence.
+    pub fn from_file_with_overrides(
+        path: impl AsRef<Path>,
+        overrides: &[ConfigOverride],
+    ) -> Result<Self, DetectError> {
+        let path = path.as_ref();
+        let config_file = std::fs::read_to_string(path).map_err(|e| DetectError::io(path, e))?;
+        let mut config = serde_yaml::from_str::<Value>(&config_file)
+            .map_err(|e| DetectError::config(Some(path.to_path_buf()), e))?;
+        for o in overrides {
+            let override_error = |message: String| {
+                DetectError::config(
+                    Some(path.to_path_buf()),
+                    format!("{} (from {}): {}", o.key, o.source, message),
+                )
+            };
+            let value = serde_yaml::from_str::<Value>(&o.value)
+                .map_err(|e| override_error(e.to_string()))?;
+            set_value(&mut config, &o.key, value).map_err(override_error)?;
+        }
+        // Unknown keys, e.g. misspelled filters or overrides, are rejected by the parser
+        let mut fit_args = serde_yaml::from_value::<FitArgs>(config)
+            .map_err(|e| DetectError::config(Some(path.to_path_buf()), e))?;
+        fit_args.roi.load_mask(path.parent())?;
+        if let Err(problems) = fit_args.validate() {
+            return Err(DetectError::config(
+                Some(path.to_path_buf()),
+                problems.join("; "),
+            ));
+        }
+        Ok(fit_args)
+    }
+
+    /// The calibration to use for the filters, None if the filters are in pixels
+    pub fn filter_calibration(&self) -> Option<Calibration> {
+        match self.filter_units {
+            LengthUnit::Pixel => None,
+            LengthUnit::Physical => self.calibration,
+        }
+    }
+
+    /// The config in the YAML format of the config file
+    pub fn to_yaml(&self) -> Result<String, DetectError> {
+        serde_yaml::to_string(self).map_err(|e| DetectError::config(None, e))
+    }
+
+    /// Checks that the values are in their valid ranges and the lower limits of the filters
+    /// are not above the upper limits. Returns the description of every problem found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut v = Validator::default();
+        v.non_negative("threshold", self.threshold);
+        v.finite("threshold", self.threshold);
+        v.finite("binarization.offset", self.binarization.offset);
+        if let Some(weights) = &self.grayscale.weights {
+            for (channel, weight) in ["red", "green", "blue"].iter().zip(weights) {
+                v.non_negative(&format!("grayscale.weights.{}", channel), *weight);
+            }
+            let sum = weights.iter().sum::<f64>();
+            v.check(
+                sum > 0.0,
+                format!("the sum of grayscale.weights must be positive, got {}", sum),
+            );
+        }
+        v.non_negative("min_fitness", self.min_fitness);
+        v.positive("dist_threshold", self.dist_threshold);
+        v.positive("radius_threshold", self.radius_threshold);
+        v.ordered(
+            "min_contour_points",
+            self.min_contour_points as f64,
+            "max_contour_points",
+            self.max_contour_points as f64,
+        );
+        v.check(
+            self.min_aspect_ratio >= 1.0,
+            format!(
+                "min_aspect_ratio must be at least 1, got {}",
+                self.min_aspect_ratio
+            ),
+        );
+        if let Some(calibration) = &self.calibration {
+            v.positive("calibration.pixel_width", calibration.pixel_width);
+            v.finite("calibration.pixel_width", calibration.pixel_width);
+            if let Some(pixel_height) = calibration.pixel_height {
+                v.positive("calibration.pixel_height", pixel_height);
+                v.finite("calibration.pixel_height", pixel_height);
+            }
+        }
+        v.check(
+            self.filter_units == LengthUnit::Pixel || self.calibration.is_some(),
+            "filter_units is physical, but no calibration is given".to_string(),
+        );
+        v.ordered(
+            "min_aspect_ratio",
+            self.min_aspect_ratio,
+            "max_aspect_ratio",
+            self.max_aspect_ratio,
+        );
+        v.non_negative("min_length", self.min_length);
+        v.ordered("min_length", self.min_length, "max_length", self.max_length);
+        v.non_negative("min_width", self.min_width);
+        v.ordered("min_width", self.min_width, "max_width", self.max_width);
+        v.finite("rotation_center_x", self.rotation_center_x);
+        v.finite("rotation_center_y", self.rotation_center_y);
+        v.non_negative("detect_radius_min", self.detect_radius_min);
+        v.ordered(
+            "detect_radius_min",
+            self.detect_radius_min,
+            "detect_radius_max",
+            self.detect_radius_max,
+        );
+        v.check(
+            self.confidence > 0.0 && self.confidence < 1.0,
+            format!(
+                "confidence must be between 0 and 1, got {}",
+                self.confidence
+            ),
+        );
+        v.positive("max_iterations", self.max_iterations as f64);
+        v.positive("sample_pairs", self.sample_pairs as f64);
+        v.check(
+            self.min_remaining_points >= 5,
+            format!(
+                "min_remaining_points must be at least 5, got {}",
+                self.min_remaining_points
+            ),
+        );
+        v.non_negative("pair_distance_min", self.pair_distance_min);
+        v.ordered(
+            "pair_distance_min",
+            self.pair_distance_min,
+            "pair_distance_max",
+            self.pair_distance_max,
+        );
+        v.positive("distance_iterations", self.distance_iterations as f64);
+        for (key, regions) in [
+            ("roi.include", &self.roi.include),
+            ("roi.exclude", &self.roi.exclude),
+        ] {
+            for (i, region) in regions.iter().enumerate() {
+                match region {
+                    Region::Rectangle {
+                        x_min,
+                        y_min,
+                        x_max,
+                        y_max,
+                    } => {
+                        let key = format!("{}[{}]", key, i);
+                        v.ordered(
+                            &format!("{}.x_min", key),
+                            *x_min,
+                            &format!("{}.x_max", key),
+                            *x_max,
+                        );
+                        v.ordered(
+                            &format!("{}.y_min", key),
+                            *y_min,
+                            &format!("{}.y_max", key),
+                            *y_max,
+                        );
+                    }
+                    Region::Circle { radius, .. } => {
+                        v.non_negative(&format!("{}[{}].radius", key, i), *radius)
+                    }
+                    Region::Polygon { points } => v.check(
+                        points.len() >= 3,
+                        format!(
+                            "{}[{}] must have at least 3 points, got {}",
+                            key,
+                            i,
+                            points.len()
+                        ),
+                    ),
+                }
+            }
+        }
+        v.check(
+            self.roi.is_loaded(),
+            "roi.mask is given, but the mask image is not loaded".to_string(),
+        );
+        v.positive("tracking.max_distance", self.tracking.max_distance);
+        v.non_negative("tracking.shape_weight", self.tracking.shape_weight);
+        v.non_negative("tracking.angle_weight", self.tracking.angle_weight);
+        if let Some(refinement) = &self.refit {
+            v.positive("refit.iterations", refinement.iterations as f64);
+        }
+        v.finish()
+    }
+}
+
+/// Sets the value at the dot separated key path, creating the missing mappings on the way.
+/// Fails if a key on the path holds a value instead of a mapping.
+fn set_value(config: &mut Value, key: &str, value: Value) -> Result<(), String> {
+    let mut current = config;
+    let mut path = vec![];
+    for part in key.split('.') {
+        if !current.is_mapping() && !current.is_null() {
+            return Err(format!(
+                "{} is a value, it has no key {}",
+                path.join("."),
+                part
+            ));
+        }
+        path.push(part);
+        // Indexing turns a null value into a mapping
+        current = &mut current[part];
+    }
+    *current = value;
+    Ok(())
+}
+
+/// Collects the problems found while validating the config.
+/// The comparisons are written so that NaN values are rejected as well.
+#[derive(Default)]
+struct Validator {
+    problems: Vec<String>,
+}
+
+impl Validator {
+    fn check(&mut self, valid: bool, message: String) {
+        if !valid {
+            self.problems.push(message);
+        }
+    }
+
+    fn positive(&mut self, key: &str, value: f64) {
+        self.check(
+            value > 0.0,
+            format!("{} must be positive, got {}", key, value),
+        );
+    }
+
+    fn non_negative(&mut self, key: &str, value: f64) {
+        self.check(
+            value >= 0.0,
+            format!("{} must not be negative, got {}", key, value),
+        );
+    }
+
+    fn finite(&mut self, key: &str, value: f64) {
+        self.check(
+            value.is_finite(),
+            format!("{} must be finite, got {}", key, value),
+        );
+    }
+
+    fn ordered(&mut self, min_key: &str, min: f64, max_key: &str, max: f64) {
+        self.check(
+            min <= max,
+            format!(
+                "{} ({}) must not be greater than {} ({})",
+                min_key, min, max_key, max
+            ),
+        );
+    }
+
+    fn finish(self) -> Result<(), Vec<String>> {
+        if self.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(self.problems)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the config to a file in the temporary directory and returns its path
+    fn write_config(name: &str, config: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "ellipse_detect_{}_{}.yaml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, config).unwrap();
+        path
+    }
+
+    fn env(name: &str, value: &str) -> (OsString, OsString) {
+        (name.into(), value.into())
+    }
+
+    fn set(s: &str) -> ConfigOverride {
+        parse_override(s).unwrap()
+    }
+
+    const CONFIG: &str = "threshold: 35
+min_fitness: 0.3
+dist_threshold: 2.0
+radius_threshold: 6.0
+max_iterations: 500
+sample_pairs: 4
+";
+
+    #[test]
+    fn env_variable_names_give_the_keys() {
+        let vars = vec![
+            env("PATH", "/usr/bin"),
+            env("ELLIPSE_DETECT_REFIT__FITTER", "taubin"),
+            env("ELLIPSE_DETECT_THRESHOLD", "40"),
+            env("ELLIPSE_DETECT_MIN_FITNESS", "0.5"),
+            env("ellipse_detect_seed", "1"),
+        ];
+        let overrides = overrides_from_vars(vars.into_iter()).unwrap();
+        let keys = overrides
+            .iter()
+            .map(|o| (o.key.as_str(), o.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                ("min_fitness", "0.5"),
+                ("refit.fitter", "taubin"),
+                ("threshold", "40")
+            ]
+        );
+        assert_eq!(
+            overrides[1].source,
+            OverrideSource::Env("ELLIPSE_DETECT_REFIT__FITTER".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_variables_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let junk = || OsString::from_vec(vec![0xff, 0xfe]);
+        let vars = vec![
+            (junk(), junk()),
+            (OsString::from("JUNK"), junk()),
+            env("ELLIPSE_DETECT_THRESHOLD", "40"),
+        ];
+        let overrides = overrides_from_vars(vars.into_iter()).unwrap();
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides[0].key, "threshold");
+
+        let vars = vec![(OsString::from("ELLIPSE_DETECT_THRESHOLD"), junk())];
+        match overrides_from_vars(vars.into_iter()) {
+            Err(DetectError::Config { message, .. }) => {
+                assert!(message.contains("ELLIPSE_DETECT_THRESHOLD"), "{}", message)
+            }
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn set_options_are_parsed() {
+        let o = set(" refit.fitter =a=b");
+        assert_eq!((o.key.as_str(), o.value.as_str()), ("refit.fitter", "a=b"));
+        assert_eq!(o.source, OverrideSource::CommandLine);
+        assert!(parse_override("=40").is_err());
+        assert!(parse_override("threshold").is_err());
+    }
+
+    #[test]
+    fn later_overrides_take_precedence() {
+        let path = write_config("precedence", CONFIG);
+        let mut overrides = overrides_from_vars(
+            vec![
+                env("ELLIPSE_DETECT_MAX_ITERATIONS", "800"),
+                env("ELLIPSE_DETECT_MIN_FITNESS", "0.4"),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        overrides.push(set("max_iterations=900"));
+        let fit_args = FitArgs::from_file_with_overrides(&path, &overrides).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Default < file < environment < --set
+        assert_eq!(fit_args.max_pair_tries, default_max_pair_tries());
+        assert_eq!(fit_args.sample_pairs, 4);
+        assert_eq!(fit_args.min_fitness, 0.4);
+        assert_eq!(fit_args.max_iterations, 900);
+        assert_eq!(fit_args.dist_threshold, 2.0);
+    }
+
+    #[test]
+    fn set_values_are_typed_as_yaml() {
+        let path = write_config("typing", CONFIG);
+        let overrides = [
+            set("threshold=40"),
+            set("seed=42"),
+            set("refit.fitter=taubin"),
+            set("binarization.invert=true"),
+            set("grayscale.weights=[1, 2, 1]"),
+        ];
+        let fit_args = FitArgs::from_file_with_overrides(&path, &overrides);
+        let wrong_type = FitArgs::from_file_with_overrides(&path, &[set("max_iterations=many")]);
+        std::fs::remove_file(&path).unwrap();
+        let fit_args = fit_args.unwrap();
+        assert_eq!(fit_args.threshold, 40.0);
+        assert_eq!(fit_args.seed, Some(42));
+        assert_eq!(fit_args.refit.unwrap().fitter, FitMethod::Taubin);
+        assert!(fit_args.binarization.invert);
+        assert_eq!(fit_args.grayscale.weights, Some([1.0, 2.0, 1.0]));
+        assert!(wrong_type.is_err());
+    }
+
+    #[test]
+    fn overriding_inside_a_value_names_the_key_and_source() {
+        let path = write_config("inside_value", CONFIG);
+        let from_env =
+            overrides_from_vars(vec![env("ELLIPSE_DETECT_THRESHOLD__X", "1")].into_iter()).unwrap();
+        let env_error = FitArgs::from_file_with_overrides(&path, &from_env);
+        let set_error = FitArgs::from_file_with_overrides(&path, &[set("sample_pairs.a.b=1")]);
+        std::fs::remove_file(&path).unwrap();
+        let message = env_error.unwrap_err().to_string();
+        assert!(message.contains("threshold.x"), "{}", message);
+        assert!(
+            message.contains("ELLIPSE_DETECT_THRESHOLD__X"),
+            "{}",
+            message
+        );
+        let message = set_error.unwrap_err().to_string();
+        assert!(message.contains("sample_pairs.a.b"), "{}", message);
+        assert!(message.contains("--set"), "{}", message);
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let fit_args: FitArgs = serde_yaml::from_str(CONFIG).unwrap();
+        assert_eq!(fit_args.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let mut fit_args: FitArgs = serde_yaml::from_str(CONFIG).unwrap();
+        fit_args.threshold = -1.0;
+        fit_args.dist_threshold = 0.0;
+        fit_args.min_length = 50.0;
+        fit_args.max_length = 10.0;
+        fit_args.confidence = 1.5;
+        fit_args.min_remaining_points = 2;
+        fit_args.pair_distance_min = f64::NAN;
+        fit_args.tracking.max_distance = -5.0;
+        let problems = fit_args.validate().unwrap_err();
+        let keys = [
+            "threshold",
+            "dist_threshold",
+            "min_length",
+            "max_length",
+            "confidence",
+            "min_remaining_points",
+            "pair_distance_min",
+            "tracking.max_distance",
+        ];
+        for key in keys {
+            assert!(
+                problems
+                    .iter()
+                    .any(|p| p.starts_with(key) || p.contains(&format!(" {} ", key))),
+                "{} not in {:?}",
+                key,
+                problems
+            );
+        }
+        // The NaN pair_distance_min fails both of its checks
+        assert_eq!(problems.len(), 8);
+    }
 }
@@ -1,71 +1,479 @@
+use std::fmt;
+
 use imageproc::point::Point;
-use nalgebra::{DVector, Matrix6, MatrixXx6, Vector6};
+use nalgebra::{Matrix3, Matrix6, MatrixXx3, Vector3, Vector6};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::DetectError, geometric_fit::fit_ellipse_geometric, robust_fit::Ellipse};
+
+/// Reason of a failed ellipse fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitError {
+    /// Less than 5 points were given, which do not determine an ellipse
+    TooFewPoints,
+    /// The points are degenerate (e.g. collinear), the scatter matrix is singular
+    SingularScatter,
+    /// The eigenvalue problem has no solution satisfying the ellipse constraint
+    NoEllipticSolution,
+    /// The fitted conic is not a real ellipse
+    NotAnEllipse,
+}
+
+impl fmt::Display for FitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            FitError::TooFewPoints => "at least 5 points are needed to fit an ellipse",
+            FitError::SingularScatter => "the scatter matrix of the points is singular",
+            FitError::NoEllipticSolution => "no eigenvector satisfies the ellipse constraint",
+            FitError::NotAnEllipse => "the fitted conic is not a real ellipse",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for FitError {}
+
+impl From<FitError> for DetectError {
+    fn from(e: FitError) -> Self {
+        match e {
+            FitError::TooFewPoints => DetectError::DegenerateInput(e.to_string()),
+            _ => DetectError::Numerical(e.to_string()),
+        }
+    }
+}
+
+/// A method for fitting an ellipse to a set of points
+pub trait EllipseFitter {
+    /// Fits an ellipse to the given points, the result is in canonical form
+    fn fit(&self, points: &[Point<f64>]) -> Result<Ellipse, FitError>;
+}
+
+/// Direct least squares fit, see `fit_ellipse_dls`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectLeastSquares;
+
+/// Taubin's algebraic fit, see `fit_ellipse_taubin`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Taubin;
+
+/// Hyper-renormalization, see `fit_ellipse_hyper_renormalization`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HyperRenormalization;
 
-use crate::robust_fit::Ellipse;
+/// Geometric (orthogonal distance) fit, started from the direct least squares fit,
+/// see `fit_ellipse_geometric`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Geometric;
+
+impl EllipseFitter for DirectLeastSquares {
+    fn fit(&self, points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+        fit_ellipse_dls(points)
+    }
+}
+
+impl EllipseFitter for Taubin {
+    fn fit(&self, points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+        fit_ellipse_taubin(points)
+    }
+}
+
+impl EllipseFitter for HyperRenormalization {
+    fn fit(&self, points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+        fit_ellipse_hyper_renormalization(points)
+    }
+}
+
+impl EllipseFitter for Geometric {
+    fn fit(&self, points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+        let initial = fit_ellipse_dls(points).or_else(|_| fit_ellipse_taubin(points))?;
+        fit_ellipse_geometric(points, &initial)
+    }
+}
+
+/// Fitting method selectable in the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitMethod {
+    /// Direct least squares fit (Fitzgibbon et al., Halíř and Flusser)
+    #[default]
+    Dls,
+    /// Taubin's algebraic fit
+    Taubin,
+    /// Kanatani's hyper-renormalization
+    HyperRenormalization,
+    /// Orthogonal distance fit with the Levenberg-Marquardt method
+    Geometric,
+}
+
+impl EllipseFitter for FitMethod {
+    fn fit(&self, points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+        match self {
+            FitMethod::Dls => DirectLeastSquares.fit(points),
+            FitMethod::Taubin => Taubin.fit(points),
+            FitMethod::HyperRenormalization => HyperRenormalization.fit(points),
+            FitMethod::Geometric => Geometric.fit(points),
+        }
+    }
+}
+
+/// Centering and scaling of a point set, which improves the conditioning of the algebraic fits
+pub(crate) struct Normalization {
+    mean_x: f64,
+    mean_y: f64,
+    scale: f64,
+}
+
+impl Normalization {
+    /// Moves the centroid of the points to the origin and scales the mean distance from it to sqrt(2)
+    pub(crate) fn new(points: &[Point<f64>]) -> Result<Self, FitError> {
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.y).sum::<f64>() / n;
+        let mean_dist = points
+            .iter()
+            .map(|p| (p.x - mean_x).hypot(p.y - mean_y))
+            .sum::<f64>()
+            / n;
+        if mean_dist.is_nan() || mean_dist <= 0.0 || mean_dist.is_infinite() {
+            return Err(FitError::SingularScatter);
+        }
+        // Collinear points have a singular covariance matrix
+        let (sxx, syy, sxy) = points.iter().fold((0.0, 0.0, 0.0), |(sxx, syy, sxy), p| {
+            let (dx, dy) = (p.x - mean_x, p.y - mean_y);
+            (sxx + dx * dx, syy + dy * dy, sxy + dx * dy)
+        });
+        if sxx * syy - sxy * sxy <= 1e-12 * (sxx + syy).powi(2) {
+            return Err(FitError::SingularScatter);
+        }
+        Ok(Normalization {
+            mean_x,
+            mean_y,
+            scale: std::f64::consts::SQRT_2 / mean_dist,
+        })
+    }
+
+    /// Normalized coordinates of the point
+    pub(crate) fn apply(&self, p: &Point<f64>) -> (f64, f64) {
+        (
+            (p.x - self.mean_x) * self.scale,
+            (p.y - self.mean_y) * self.scale,
+        )
+    }
+
+    /// Transforms the conic coefficients [A, B, C, D, E, F] from the normalized coordinates
+    /// back to the original coordinates
+    pub(crate) fn denormalize_conic(&self, coeffs: [f64; 6]) -> [f64; 6] {
+        let (mx, my, s) = (self.mean_x, self.mean_y, self.scale);
+        let (a, b, c) = (coeffs[0] * s * s, coeffs[1] * s * s, coeffs[2] * s * s);
+        let (d, e, f) = (coeffs[3] * s, coeffs[4] * s, coeffs[5]);
+        [
+            a,
+            b,
+            c,
+            d - 2.0 * a * mx - b * my,
+            e - b * mx - 2.0 * c * my,
+            a * mx * mx + b * mx * my + c * my * my - d * mx - e * my + f,
+        ]
+    }
+}
 
 /// Fits an ellipse to the given points using the direct least squares method.
+/// The returned ellipse is in canonical form (see `Ellipse::canonical`).
 /// Based on:
 /// Halır, R. and Flusser, J., 1998, February. Numerically stable direct least squares fitting of ellipses. In Proc. 6th International Conference in Central Europe on Computer Graphics and Visualization. WSCG (Vol. 98, pp. 125-132). Citeseer.
-pub fn fit_ellipse_dls(points: &[Point<f64>]) -> Option<Ellipse> {
-    let D: MatrixXx6<f64> = MatrixXx6::from_columns(&[
-        DVector::from_iterator(points.len(), points.iter().map(|p| p.x * p.x)),
-        DVector::from_iterator(points.len(), points.iter().map(|p| p.x * p.y)),
-        DVector::from_iterator(points.len(), points.iter().map(|p| p.y * p.y)),
-        DVector::from_iterator(points.len(), points.iter().map(|p| p.x)),
-        DVector::from_iterator(points.len(), points.iter().map(|p| p.y)),
-        DVector::from_element(points.len(), 1.0),
-    ]);
-    let mut S = D.transpose() * D;
-    let mut C = Matrix6::from_element(0.0);
-    C.m13 = 2.0;
-    C.m22 = -1.0;
-    C.m31 = 2.0;
-    let is_inverse_success = S.try_inverse_mut();
-    if !is_inverse_success {
-        return None;
-    }
-    let to_eig_orig = S * C;
-    // Find eigenvlues using the QR algorithm
-    let mut to_eig = to_eig_orig.hessenberg().h();
+pub fn fit_ellipse_dls(points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+    if points.len() < 5 {
+        return Err(FitError::TooFewPoints);
+    }
+
+    // Center and scale the points, so that the scatter matrices are well conditioned
+    let norm = Normalization::new(points)?;
+    let normalized = points.iter().map(|p| norm.apply(p));
+
+    // Quadratic and linear parts of the design matrix
+    let mut d1 = MatrixXx3::<f64>::zeros(points.len());
+    let mut d2 = MatrixXx3::<f64>::zeros(points.len());
+    for (i, (x, y)) in normalized.enumerate() {
+        d1.set_row(i, &Vector3::new(x * x, x * y, y * y).transpose());
+        d2.set_row(i, &Vector3::new(x, y, 1.0).transpose());
+    }
+    let s1 = d1.transpose() * &d1;
+    let s2 = d1.transpose() * &d2;
+    let s3 = d2.transpose() * &d2;
+    let s3_inv = s3.try_inverse().ok_or(FitError::SingularScatter)?;
+    let t = -s3_inv * s2.transpose();
+    let m = s1 + s2 * t;
+    // Premultiply with the inverse of the constraint matrix C1 = [[0, 0, 2], [0, -1, 0], [2, 0, 0]]
+    let m = Matrix3::from_rows(&[m.row(2) / 2.0, -m.row(1), m.row(0) / 2.0]);
+
+    // The solution is the eigenvector satisfying the ellipse constraint 4ac - b^2 > 0
+    let a1 = m
+        .complex_eigenvalues()
+        .iter()
+        .filter(|l| l.im.abs() <= 1e-12 * (1.0 + l.re.abs()))
+        .filter_map(|l| null_vector(&(m - Matrix3::identity() * l.re)))
+        .map(|v| (4.0 * v.x * v.z - v.y * v.y, v))
+        .filter(|(cond, _)| *cond > 0.0)
+        .max_by(|(c1, _), (c2, _)| c1.partial_cmp(c2).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(_, v)| v)
+        .ok_or(FitError::NoEllipticSolution)?;
+    let a2 = t * a1;
+
+    let coeffs = norm.denormalize_conic([a1.x, a1.y, a1.z, a2.x, a2.y, a2.z]);
+    Ellipse::from_conic(coeffs).ok_or(FitError::NotAnEllipse)
+}
+
+/// Fits an ellipse to the given points with Taubin's method, which has smaller bias
+/// than the direct least squares fit, but may return a hyperbola for poor samples.
+/// Based on:
+/// Taubin, G., 1991. Estimation of planar curves, surfaces, and nonplanar space curves defined by implicit equations with applications to edge and range image segmentation. IEEE Transactions on Pattern Analysis and Machine Intelligence, 13(11), pp.1115-1138.
+pub fn fit_ellipse_taubin(points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+    if points.len() < 5 {
+        return Err(FitError::TooFewPoints);
+    }
+    let norm = Normalization::new(points)?;
+    let n = points.len() as f64;
+    let (mut m, mut nmat) = (Matrix6::zeros(), Matrix6::zeros());
+    for p in points {
+        let (x, y) = norm.apply(p);
+        let xi = xi_vector(x, y);
+        m += xi * xi.transpose() / n;
+        nmat += v0_matrix(x, y) / n;
+    }
+    let theta = max_generalized_eigenvector(&m, &nmat).ok_or(FitError::SingularScatter)?;
+    theta_to_ellipse(&norm, &theta)
+}
+
+/// Fits an ellipse to the given points with hyper-renormalization, which iteratively
+/// reweights the points and removes the second order bias of the algebraic fit.
+/// Based on:
+/// Kanatani, K., Al-Sharadqah, A., Chernov, N. and Sugaya, Y., 2012. Renormalization returns: Hyper-renormalization and its applications. In European Conference on Computer Vision (pp. 384-397). Springer.
+pub fn fit_ellipse_hyper_renormalization(points: &[Point<f64>]) -> Result<Ellipse, FitError> {
+    if points.len() < 5 {
+        return Err(FitError::TooFewPoints);
+    }
+    let norm = Normalization::new(points)?;
+    let n = points.len() as f64;
+    let samples = points
+        .iter()
+        .map(|p| {
+            let (x, y) = norm.apply(p);
+            (xi_vector(x, y), v0_matrix(x, y))
+        })
+        .collect::<Vec<_>>();
+    let e13 = Vector6::new(1.0, 0.0, 1.0, 0.0, 0.0, 0.0);
+    let sym = |a: Matrix6<f64>| (a + a.transpose()) / 2.0;
+
+    let mut weights = vec![1.0; samples.len()];
+    let mut prev_theta: Option<Vector6<f64>> = None;
+    let mut theta = Vector6::zeros();
     for _ in 0..20 {
-        let qr = to_eig.qr();
-        to_eig = qr.r() * qr.q();
+        let m = samples
+            .iter()
+            .zip(weights.iter())
+            .fold(Matrix6::zeros(), |acc, ((xi, _), w)| {
+                acc + xi * xi.transpose() * *w / n
+            });
+        let m5_inv = truncated_pseudo_inverse(&m);
+        let nmat =
+            samples
+                .iter()
+                .zip(weights.iter())
+                .fold(Matrix6::zeros(), |acc, ((xi, v0), w)| {
+                    let first = (v0 + sym(xi * e13.transpose()) * 2.0) * *w / n;
+                    let second = (v0 * xi.dot(&(m5_inv * xi))
+                        + sym(v0 * m5_inv * xi * xi.transpose()) * 2.0)
+                        * (w * w / (n * n));
+                    acc + first - second
+                });
+        theta = max_generalized_eigenvector(&m, &nmat).ok_or(FitError::SingularScatter)?;
+        if let Some(prev) = prev_theta {
+            if (theta - prev).norm().min((theta + prev).norm()) < 1e-10 {
+                break;
+            }
+        }
+        prev_theta = Some(theta);
+        weights = samples
+            .iter()
+            .map(|(_, v0)| 1.0 / theta.dot(&(v0 * theta)).max(f64::EPSILON))
+            .collect();
     }
-    let eigvals = [
-        to_eig.m11, to_eig.m22, to_eig.m33, to_eig.m44, to_eig.m55, to_eig.m66,
+    theta_to_ellipse(&norm, &theta)
+}
+
+/// The vector (x^2, 2xy, y^2, 2x, 2y, 1) of the algebraic fits
+fn xi_vector(x: f64, y: f64) -> Vector6<f64> {
+    Vector6::new(x * x, 2.0 * x * y, y * y, 2.0 * x, 2.0 * y, 1.0)
+}
+
+/// Normalized covariance matrix of the xi vector, for isotropic noise of the point
+fn v0_matrix(x: f64, y: f64) -> Matrix6<f64> {
+    #[rustfmt::skip]
+    let v0 = Matrix6::new(
+        x * x, x * y,         0.0,   x,   0.0, 0.0,
+        x * y, x * x + y * y, x * y, y,   x,   0.0,
+        0.0,   x * y,         y * y, 0.0, y,   0.0,
+        x,     y,             0.0,   1.0, 0.0, 0.0,
+        0.0,   x,             y,     0.0, 1.0, 0.0,
+        0.0,   0.0,           0.0,   0.0, 0.0, 0.0,
+    );
+    v0 * 4.0
+}
+
+/// Converts the parameters of the xi vector representation to an ellipse in the original coordinates
+fn theta_to_ellipse(norm: &Normalization, theta: &Vector6<f64>) -> Result<Ellipse, FitError> {
+    let coeffs = [
+        theta[0],
+        2.0 * theta[1],
+        theta[2],
+        2.0 * theta[3],
+        2.0 * theta[4],
+        theta[5],
     ];
-    let pos_eigval = eigvals.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    Ellipse::from_conic(norm.denormalize_conic(coeffs)).ok_or(FitError::NotAnEllipse)
+}
 
-    // Then find the eigenvector using the inverse iteration method
-    let mut eigvec = Vector6::from_element(1.0).normalize();
-    let iter_mat = (to_eig_orig - pos_eigval * Matrix6::identity()).try_inverse()?;
-    for _ in 0..20 {
-        eigvec = iter_mat * eigvec;
-        eigvec.normalize_mut();
-    }
-    let factor = eigvec.transpose() * C * eigvec;
-    let eigvec = eigvec / factor.x.sqrt();
-
-    let [a, b, c, d, e, f] = eigvec.data.0[0];
-    let axis_a = (2.0
-        * (a * e * e + c * d * d - b * d * e - f)
-        * ((a + c) + ((a - c).powi(2) + b * b).sqrt()))
-    .sqrt();
-    let axis_b = (2.0
-        * (a * e * e + c * d * d - b * d * e - f)
-        * ((a + c) - ((a - c).powi(2) + b * b).sqrt()))
-    .sqrt();
-    let center_x = b * e - 2.0 * c * d;
-    let center_y = b * d - 2.0 * a * e;
-    let theta = if b != 0.0 {
-        ((c - a - ((a - c).powi(2) + b * b).sqrt()) / b).atan()
-    } else {
-        if a < c {
-            0.0
-        } else {
-            std::f64::consts::PI / 2.0
+/// Solves N θ = μ M θ for a symmetric N and a positive definite M and returns the unit
+/// eigenvector of the eigenvalue with the largest absolute value.
+/// If M is singular (the points lie exactly on a conic), its null vector is returned.
+fn max_generalized_eigenvector(m: &Matrix6<f64>, n: &Matrix6<f64>) -> Option<Vector6<f64>> {
+    let cholesky = match m.cholesky() {
+        Some(cholesky) => cholesky,
+        None => {
+            let eig = m.symmetric_eigen();
+            let (min_index, _) = eig.eigenvalues.argmin();
+            return Some(eig.eigenvectors.column(min_index).into_owned());
         }
     };
+    let l_inv = cholesky.l().try_inverse()?;
+    let sym = l_inv * n * l_inv.transpose();
+    let eig = ((sym + sym.transpose()) / 2.0).symmetric_eigen();
+    let (max_index, _) = eig.eigenvalues.iamax_full();
+    let theta = l_inv.transpose() * eig.eigenvectors.column(max_index);
+    let theta_norm = theta.norm();
+    if theta_norm > 0.0 && theta_norm.is_finite() {
+        Some(theta / theta_norm)
+    } else {
+        None
+    }
+}
+
+/// Pseudo-inverse of M, truncated to rank 5 by dropping its smallest eigenvalue
+fn truncated_pseudo_inverse(m: &Matrix6<f64>) -> Matrix6<f64> {
+    let eig = m.symmetric_eigen();
+    let (min_index, _) = eig.eigenvalues.argmin();
+    (0..6)
+        .filter(|&i| i != min_index && eig.eigenvalues[i].abs() > f64::EPSILON)
+        .fold(Matrix6::zeros(), |acc, i| {
+            let v = eig.eigenvectors.column(i);
+            acc + v * v.transpose() / eig.eigenvalues[i]
+        })
+}
+
+/// Unit vector spanning the null space of a (numerically) singular 3x3 matrix
+fn null_vector(mat: &Matrix3<f64>) -> Option<Vector3<f64>> {
+    let svd = mat.svd(false, true);
+    let v_t = svd.v_t?;
+    let (min_index, _) = svd.singular_values.argmin();
+    Some(v_t.row(min_index).transpose())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ellipse_arc_points, ellipse_points};
 
-    Some(Ellipse::new(center_x, center_y, axis_a, axis_b, theta))
+    const FITTERS: [FitMethod; 3] = [
+        FitMethod::Dls,
+        FitMethod::Taubin,
+        FitMethod::HyperRenormalization,
+    ];
+
+    fn assert_close(fitted: &Ellipse, expected: &Ellipse, tolerance: f64, angle_tolerance: f64) {
+        let pi = std::f64::consts::PI;
+        let angle = (fitted.theta - expected.theta).rem_euclid(pi);
+        let close = (fitted.x - expected.x).abs() < tolerance
+            && (fitted.y - expected.y).abs() < tolerance
+            && (fitted.a - expected.a).abs() < tolerance
+            && (fitted.b - expected.b).abs() < tolerance
+            && angle.min(pi - angle) < angle_tolerance;
+        assert!(close, "{:?} != {:?}", fitted, expected);
+    }
+
+    #[test]
+    fn exact_points_give_back_the_ellipse() {
+        let expected = Ellipse::new(50.0, 40.0, 30.0, 12.0, 0.6);
+        let points = ellipse_points(&expected, 50, 0.0, &fastrand::Rng::with_seed(11));
+        for fitter in FITTERS {
+            assert_close(&fitter.fit(&points).unwrap(), &expected, 1e-6, 1e-8);
+        }
+    }
+
+    #[test]
+    fn noisy_points_fit_within_tolerance() {
+        let expected = Ellipse::new(-20.0, 130.0, 45.0, 20.0, 2.3);
+        let points = ellipse_points(&expected, 300, 0.5, &fastrand::Rng::with_seed(11));
+        for fitter in FITTERS {
+            assert_close(&fitter.fit(&points).unwrap(), &expected, 0.3, 0.01);
+        }
+    }
+
+    #[test]
+    fn partial_arc_fits() {
+        let expected = Ellipse::new(10.0, 5.0, 25.0, 10.0, 1.0);
+        let rng = fastrand::Rng::with_seed(11);
+        let exact = ellipse_arc_points(&expected, 40, (-0.5, 1.6), 0.0, &rng);
+        let noisy = ellipse_arc_points(&expected, 100, (-0.5, 1.6), 0.05, &rng);
+        for fitter in FITTERS {
+            assert_close(&fitter.fit(&exact).unwrap(), &expected, 1e-6, 1e-8);
+            assert_close(&fitter.fit(&noisy).unwrap(), &expected, 1.0, 0.05);
+        }
+    }
+
+    #[test]
+    fn too_few_points_are_rejected() {
+        let expected = Ellipse::new(0.0, 0.0, 5.0, 3.0, 0.0);
+        let points = ellipse_points(&expected, 4, 0.0, &fastrand::Rng::with_seed(11));
+        for fitter in FITTERS {
+            assert_eq!(fitter.fit(&points), Err(FitError::TooFewPoints));
+        }
+    }
+
+    #[test]
+    fn degenerate_points_are_rejected() {
+        let horizontal = (0..20)
+            .map(|i| Point::new(i as f64, 3.0))
+            .collect::<Vec<_>>();
+        let diagonal = (0..20)
+            .map(|i| Point::new(0.7 * i as f64 + 1.0, 1.3 * i as f64 - 2.0))
+            .collect::<Vec<_>>();
+        let coincident = vec![Point::new(2.0, 3.0); 10];
+        for fitter in FITTERS {
+            for points in [&horizontal, &diagonal, &coincident] {
+                assert_eq!(fitter.fit(points), Err(FitError::SingularScatter));
+            }
+        }
+    }
+
+    #[test]
+    fn other_conics_are_rejected() {
+        let parabola = (-10..=10)
+            .map(|i| Point::new(i as f64, 0.5 * (i * i) as f64))
+            .collect::<Vec<_>>();
+        let hyperbola = (1..=10)
+            .flat_map(|i| {
+                let t = 0.4 * i as f64;
+                [Point::new(t, 1.0 / t), Point::new(-t, -1.0 / t)]
+            })
+            .collect::<Vec<_>>();
+        // The direct least squares fit only searches among ellipses
+        assert_eq!(
+            fit_ellipse_dls(&parabola),
+            Err(FitError::NoEllipticSolution)
+        );
+        for fit in [fit_ellipse_taubin, fit_ellipse_hyper_renormalization] {
+            assert_eq!(fit(&parabola), Err(FitError::NotAnEllipse));
+            assert_eq!(fit(&hyperbola), Err(FitError::NotAnEllipse));
+        }
+    }
 }
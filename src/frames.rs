@@ -0,0 +1,396 @@
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageBuffer, ImageError, ImageFormat,
+};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    ColorType,
+};
+
+use crate::{
+    detect::{image_error, load_image},
+    error::DetectError,
+};
+
+/// A frame of an image sequence: a single image file or a page of a multi-page TIFF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Index of the frame in the sequence
+    pub index: usize,
+
+    /// Pathname of the file containing the frame
+    pub file: PathBuf,
+
+    /// Page of the TIFF stack, None for single images
+    pub page: Option<usize>,
+}
+
+impl Frame {
+    /// Loads the image of the frame
+    pub fn load(&self) -> Result<DynamicImage, DetectError> {
+        match self.page {
+            Some(page) => load_tiff_page(&self.file, page),
+            None => load_image(&self.file),
+        }
+    }
+
+    /// Name of the frame for the output files: the file stem, followed by the page for stacks
+    pub fn name(&self) -> String {
+        let stem = self.file.file_stem().unwrap_or_default().to_string_lossy();
+        match self.page {
+            Some(page) => format!("{}_{:05}", stem, page),
+            None => stem.into_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.page {
+            Some(page) => write!(f, "{}[{}]", self.file.display(), page),
+            None => write!(f, "{}", self.file.display()),
+        }
+    }
+}
+
+/// Frames of the given image files, indexed in the given order
+pub fn file_frames(files: Vec<PathBuf>) -> Vec<Frame> {
+    files
+        .into_iter()
+        .enumerate()
+        .map(|(index, file)| Frame {
+            index,
+            file,
+            page: None,
+        })
+        .collect()
+}
+
+/// Frames of a multi-page TIFF file, one for each page
+pub fn stack_frames(path: impl AsRef<Path>) -> Result<Vec<Frame>, DetectError> {
+    let path = path.as_ref();
+    let mut decoder = open_tiff(path)?;
+    let mut pages = 1;
+    while decoder.more_images() {
+        decoder.next_image().map_err(|e| tiff_error(path, e))?;
+        pages += 1;
+    }
+    Ok((0..pages)
+        .map(|page| Frame {
+            index: page,
+            file: path.to_path_buf(),
+            page: Some(page),
+        })
+        .collect())
+}
+
+/// Frames of the numbered files matching a printf style pattern, e.g. `frame_%05d.png`.
+/// The numbering starts at the first existing file among the numbers 0 to 4
+/// and ends before the first missing number. The frame index is the number of the file.
+/// Fails if the pattern is invalid or none of the first numbers exist.
+pub fn numbered_frames(pattern: &str) -> Result<Vec<Frame>, DetectError> {
+    // Reports an invalid pattern even if no files are found
+    format_pattern(pattern, 0)?;
+    let start = (0..5)
+        .find(|&n| {
+            format_pattern(pattern, n)
+                .map(|f| f.is_file())
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| {
+            DetectError::config(
+                None,
+                format!(
+                    "no files match the frame pattern {:?} with the numbers 0 to 4",
+                    pattern
+                ),
+            )
+        })?;
+    let mut frames = vec![];
+    for index in start.. {
+        let file = format_pattern(pattern, index)?;
+        if !file.is_file() {
+            break;
+        }
+        frames.push(Frame {
+            index,
+            file,
+            page: None,
+        });
+    }
+    Ok(frames)
+}
+
+/// Replaces the `%d` or `%0Nd` placeholder of the pattern with the number, `%%` is a literal `%`
+fn format_pattern(pattern: &str, number: usize) -> Result<PathBuf, DetectError> {
+    let invalid = || {
+        DetectError::config(
+            None,
+            format!(
+                "invalid frame pattern {:?}, expected a single %d or %0Nd",
+                pattern
+            ),
+        )
+    };
+    let mut result = String::new();
+    let mut placeholders = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        if chars.peek() == Some(&'%') {
+            chars.next();
+            result.push('%');
+            continue;
+        }
+        let mut width = String::new();
+        while let Some(&digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            width.push(digit);
+            chars.next();
+        }
+        if chars.next() != Some('d') {
+            return Err(invalid());
+        }
+        let width = width.parse::<usize>().unwrap_or(0);
+        result.push_str(&format!("{:0width$}", number, width = width));
+        placeholders += 1;
+    }
+    if placeholders != 1 {
+        return Err(invalid());
+    }
+    Ok(PathBuf::from(result))
+}
+
+fn open_tiff(path: &Path) -> Result<Decoder<BufReader<File>>, DetectError> {
+    let file = File::open(path).map_err(|e| DetectError::io(path, e))?;
+    Decoder::new(BufReader::new(file)).map_err(|e| tiff_error(path, e))
+}
+
+thread_local! {
+    /// Decoder of the stack read last on this thread. It keeps the offsets of the pages it has
+    /// passed, so reading the pages of a stack one after the other does not walk through all
+    /// the previous pages again for each page. It is reopened if the file has changed.
+    static STACK_DECODER: RefCell<Option<CachedDecoder>> = const { RefCell::new(None) };
+}
+
+/// An open stack, along with the size and modification time of its file when it was opened
+struct CachedDecoder {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    len: u64,
+    decoder: Decoder<BufReader<File>>,
+}
+
+/// Decodes the given page of a multi-page TIFF file
+fn load_tiff_page(path: &Path, page: usize) -> Result<DynamicImage, DetectError> {
+    STACK_DECODER.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        let metadata = std::fs::metadata(path).map_err(|e| DetectError::io(path, e))?;
+        let (modified, len) = (metadata.modified().ok(), metadata.len());
+        let unchanged = |c: &CachedDecoder| {
+            c.path == path && c.modified.is_some() && c.modified == modified && c.len == len
+        };
+        if !cached.as_ref().is_some_and(unchanged) {
+            *cached = Some(CachedDecoder {
+                path: path.to_path_buf(),
+                modified,
+                len,
+                decoder: open_tiff(path)?,
+            });
+        }
+        let decoder = &mut cached.as_mut().unwrap().decoder;
+        let result = decoder
+            .seek_to_image(page)
+            .map_err(|e| tiff_error(path, e))
+            .and_then(|()| decode_page(decoder, path));
+        if result.is_err() {
+            *cached = None;
+        }
+        result
+    })
+}
+
+/// Decodes the current page of the decoder
+fn decode_page(
+    decoder: &mut Decoder<BufReader<File>>,
+    path: &Path,
+) -> Result<DynamicImage, DetectError> {
+    let (width, height) = decoder.dimensions().map_err(|e| tiff_error(path, e))?;
+    let color_type = decoder.colortype().map_err(|e| tiff_error(path, e))?;
+    let data = decoder.read_image().map_err(|e| tiff_error(path, e))?;
+    let unsupported = || {
+        tiff_error(
+            path,
+            format!("unsupported TIFF color type {:?}", color_type),
+        )
+    };
+    let img = match (color_type, data) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16)
+        }
+        // The image crate has no floating point gray images, so the gray value is copied
+        // to the color channels, which gives the same value when converted back to gray
+        (ColorType::Gray(32), DecodingResult::F32(data)) => {
+            let data = data.iter().flat_map(|&v| [v, v, v]).collect();
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb32F)
+        }
+        (ColorType::GrayA(32), DecodingResult::F32(data)) => {
+            let data = data
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect();
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba32F)
+        }
+        (ColorType::RGB(32), DecodingResult::F32(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb32F)
+        }
+        (ColorType::RGBA(32), DecodingResult::F32(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba32F)
+        }
+        _ => return Err(unsupported()),
+    };
+    img.ok_or_else(unsupported)
+}
+
+fn tiff_error(path: &Path, e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> DetectError {
+    let e = DecodingError::new(ImageFormatHint::Exact(ImageFormat::Tiff), e);
+    image_error(path, ImageError::Decoding(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_pattern_replaces_placeholder() {
+        let format = |pattern, number| {
+            let path = format_pattern(pattern, number).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        assert_eq!(format("frame_%d.png", 7), "frame_7.png");
+        assert_eq!(format("frame_%d.png", 1234), "frame_1234.png");
+        assert_eq!(format("frame_%05d.png", 42), "frame_00042.png");
+        assert_eq!(format("frame_%02d.png", 123), "frame_123.png");
+        assert_eq!(format("dir_%%/%03d.tif", 5), "dir_%/005.tif");
+        assert_eq!(format("100%%_%d", 0), "100%_0");
+    }
+
+    #[test]
+    fn format_pattern_rejects_invalid_patterns() {
+        for pattern in [
+            "frame.png",
+            "frame_%%d.png",
+            "frame_%d_%d.png",
+            "frame_%x.png",
+            "frame_%05.png",
+            "frame_%",
+            "frame_%-5d.png",
+        ] {
+            assert!(
+                matches!(format_pattern(pattern, 1), Err(DetectError::Config { .. })),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn stack_pages_are_loaded_in_any_order() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let path =
+            std::env::temp_dir().join(format!("ellipse_detect_stack_{}.tif", std::process::id()));
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = TiffEncoder::new(file).unwrap();
+            for page in 0..6u8 {
+                encoder
+                    .write_image::<colortype::Gray8>(2, 2, &[page * 10; 4])
+                    .unwrap();
+            }
+        }
+        let frames = stack_frames(&path).unwrap();
+        assert_eq!(frames.len(), 6);
+        let values = [3, 0, 5, 5, 1, 4, 2]
+            .iter()
+            .map(|&page| frames[page].load().unwrap().to_luma8().get_pixel(1, 1)[0])
+            .collect::<Vec<_>>();
+        let missing = Frame {
+            page: Some(6),
+            ..frames[0].clone()
+        }
+        .load();
+        let after_error = frames[2].load().unwrap().to_luma8().get_pixel(0, 0)[0];
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(values, vec![30, 0, 50, 50, 10, 40, 20]);
+        assert!(matches!(missing, Err(DetectError::Decode { .. })));
+        assert_eq!(after_error, 20);
+    }
+
+    #[test]
+    fn float_pages_are_loaded() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let path = std::env::temp_dir().join(format!(
+            "ellipse_detect_float_stack_{}.tif",
+            std::process::id()
+        ));
+        let gray = [0.0, 0.25, 0.5, 1.0, 0.75, 0.125];
+        let rgb = gray
+            .iter()
+            .flat_map(|&v| [v, v / 2.0, 0.0])
+            .collect::<Vec<_>>();
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = TiffEncoder::new(file).unwrap();
+            encoder
+                .write_image::<colortype::Gray32Float>(3, 2, &gray)
+                .unwrap();
+            encoder
+                .write_image::<colortype::RGB32Float>(3, 2, &rgb)
+                .unwrap();
+        }
+        let frames = stack_frames(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        let pages = frames.iter().map(|f| f.load().unwrap()).collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+
+        let gray_page = pages[0].to_rgb32f();
+        assert_eq!((gray_page.width(), gray_page.height()), (3, 2));
+        for (p, &v) in gray_page.pixels().zip(&gray) {
+            assert_eq!(p.0, [v, v, v]);
+        }
+        assert_eq!(pages[1].to_rgb32f().into_raw(), rgb);
+    }
+}
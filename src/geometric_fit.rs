@@ -0,0 +1,260 @@
+use imageproc::point::Point;
+use nalgebra::{Matrix5, Vector5};
+use serde::Serialize;
+
+use crate::{fit_ellipse::FitError, robust_fit::Ellipse};
+
+/// Fits an ellipse to the given points by minimizing the sum of squared orthogonal distances
+/// with the Levenberg-Marquardt method, starting from the `initial` ellipse.
+/// In each step, the closest perimeter points are found first, then the parameters
+/// (center, axes, angle) are updated with the closest points held fixed.
+/// Based on:
+/// Ahn, S.J., Rauh, W. and Warnecke, H.J., 2001. Least-squares orthogonal distances fitting of circle, sphere, ellipse, hyperbola, and parabola. Pattern Recognition, 34(12), pp.2283-2303.
+pub fn fit_ellipse_geometric(
+    points: &[Point<f64>],
+    initial: &Ellipse,
+) -> Result<Ellipse, FitError> {
+    if points.len() < 5 {
+        return Err(FitError::TooFewPoints);
+    }
+    let mut params = Vector5::new(initial.x, initial.y, initial.a, initial.b, initial.theta);
+    let mut cost = total_cost(points, &params);
+    let mut lambda = 1e-3;
+
+    for _ in 0..100 {
+        let (jtj, jtr) = normal_equations(points, &params);
+
+        let mut improved = false;
+        while lambda < 1e10 {
+            let damped = jtj + Matrix5::from_diagonal(&jtj.diagonal()) * lambda;
+            let step = match damped.try_inverse() {
+                Some(inv) => -(inv * jtr),
+                None => break,
+            };
+            let candidate = params + step;
+            let new_cost = if candidate[2] > 0.0 && candidate[3] > 0.0 {
+                total_cost(points, &candidate)
+            } else {
+                f64::INFINITY
+            };
+            if new_cost < cost {
+                let converged = (cost - new_cost) <= 1e-12 * cost || step.norm() < 1e-10;
+                params = candidate;
+                cost = new_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    let ellipse = to_ellipse(&params);
+    if params.iter().all(|v| v.is_finite()) {
+        Ok(ellipse.canonical())
+    } else {
+        Err(FitError::NotAnEllipse)
+    }
+}
+
+/// Standard deviations of the ellipse parameters, in pixels (radians for `theta`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Uncertainty {
+    pub x: f64,
+    pub y: f64,
+    pub a: f64,
+    pub b: f64,
+    pub theta: f64,
+}
+
+/// Estimates the standard deviations of the parameters of the ellipse fitted to the given points.
+/// The covariance is approximated by s^2 (J^T J)^-1, where J is the Jacobian of the orthogonal
+/// distances with respect to the parameters and s^2 is the residual variance. Returns None if
+/// there are not enough points or the parameters are not determined by the points.
+pub fn fit_uncertainty(points: &[Point<f64>], ellipse: &Ellipse) -> Option<Uncertainty> {
+    if points.len() <= 5 {
+        return None;
+    }
+    let params = Vector5::new(ellipse.x, ellipse.y, ellipse.a, ellipse.b, ellipse.theta);
+    let (jtj, _) = normal_equations(points, &params);
+    let variance = total_cost(points, &params) / (points.len() - 5) as f64;
+    let cov = jtj.try_inverse()? * variance;
+    let std = cov.diagonal().map(|v| v.max(0.0).sqrt());
+    if !std.iter().all(|v| v.is_finite()) {
+        return None;
+    }
+    Some(Uncertainty {
+        x: std[0],
+        y: std[1],
+        a: std[2],
+        b: std[3],
+        theta: std[4],
+    })
+}
+
+/// J^T J and J^T r of the signed orthogonal distances r, where J is the Jacobian of the
+/// distances with respect to the parameters (center, axes, angle)
+fn normal_equations(points: &[Point<f64>], params: &Vector5<f64>) -> (Matrix5<f64>, Vector5<f64>) {
+    let ellipse = to_ellipse(params);
+    let (sin, cos) = params[4].sin_cos();
+    let (a, b) = (params[2], params[3]);
+    let mut jtj = Matrix5::zeros();
+    let mut jtr = Vector5::zeros();
+    for p in points {
+        let t = foot_point_param(&ellipse, p.x, p.y);
+        let foot = ellipse.point_at(t);
+        let (tsin, tcos) = t.sin_cos();
+        // Outward unit normal of the perimeter at the closest point
+        let (nu, nv) = (b * tcos, a * tsin);
+        let n_len = nu.hypot(nv);
+        let (nx, ny) = ((nu * cos - nv * sin) / n_len, (nu * sin + nv * cos) / n_len);
+        let distance = (p.x - foot.x) * nx + (p.y - foot.y) * ny;
+        // Derivatives of the perimeter point with respect to the parameters, the distance
+        // changes with the negative of their normal component
+        let dx = Vector5::new(
+            1.0,
+            0.0,
+            tcos * cos,
+            -tsin * sin,
+            -a * tcos * sin - b * tsin * cos,
+        );
+        let dy = Vector5::new(
+            0.0,
+            1.0,
+            tcos * sin,
+            tsin * cos,
+            a * tcos * cos - b * tsin * sin,
+        );
+        let grad = -(dx * nx + dy * ny);
+        jtj += grad * grad.transpose();
+        jtr += grad * distance;
+    }
+    (jtj, jtr)
+}
+
+fn to_ellipse(params: &Vector5<f64>) -> Ellipse {
+    Ellipse::new(params[0], params[1], params[2], params[3], params[4])
+}
+
+/// Sum of the squared orthogonal distances of the points from the ellipse
+fn total_cost(points: &[Point<f64>], params: &Vector5<f64>) -> f64 {
+    let ellipse = to_ellipse(params);
+    points
+        .iter()
+        .map(|p| {
+            let foot = ellipse.point_at(foot_point_param(&ellipse, p.x, p.y));
+            (p.x - foot.x).powi(2) + (p.y - foot.y).powi(2)
+        })
+        .sum()
+}
+
+/// Parameter t of the perimeter point closest to (px, py), found with the iterative
+/// method of Chatfield in the first quadrant and then mirrored to the quadrant of the point
+fn foot_point_param(e: &Ellipse, px: f64, py: f64) -> f64 {
+    let (sin, cos) = e.theta.sin_cos();
+    let (dx, dy) = (px - e.x, py - e.y);
+    let u = dx * cos + dy * sin;
+    let v = -dx * sin + dy * cos;
+    let (pu, pv) = (u.abs(), v.abs());
+    let (a, b) = (e.a, e.b);
+
+    let mut tx = std::f64::consts::FRAC_1_SQRT_2;
+    let mut ty = std::f64::consts::FRAC_1_SQRT_2;
+    for _ in 0..5 {
+        let x = a * tx;
+        let y = b * ty;
+        let ex = (a * a - b * b) * tx.powi(3) / a;
+        let ey = (b * b - a * a) * ty.powi(3) / b;
+        let (rx, ry) = (x - ex, y - ey);
+        let (qx, qy) = (pu - ex, pv - ey);
+        let r = ry.hypot(rx);
+        let q = qy.hypot(qx);
+        if q == 0.0 {
+            break;
+        }
+        tx = ((qx * r / q + ex) / a).clamp(0.0, 1.0);
+        ty = ((qy * r / q + ey) / b).clamp(0.0, 1.0);
+        let t = ty.hypot(tx);
+        tx /= t;
+        ty /= t;
+    }
+    (ty.copysign(v)).atan2(tx.copysign(u))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fit_ellipse::fit_ellipse_dls, test_util::ellipse_points};
+
+    fn cost(points: &[Point<f64>], e: &Ellipse) -> f64 {
+        total_cost(points, &Vector5::new(e.x, e.y, e.a, e.b, e.theta))
+    }
+
+    #[test]
+    fn improves_on_the_algebraic_fit() {
+        let expected = Ellipse::new(40.0, -10.0, 30.0, 8.0, 0.9);
+        for seed in 0..5 {
+            let points = ellipse_points(&expected, 100, 1.0, &fastrand::Rng::with_seed(seed));
+            let initial = fit_ellipse_dls(&points).unwrap();
+            let fitted = fit_ellipse_geometric(&points, &initial).unwrap();
+            assert!(cost(&points, &fitted) <= cost(&points, &initial));
+        }
+    }
+
+    #[test]
+    fn converges_on_exact_points() {
+        let expected = Ellipse::new(40.0, -10.0, 30.0, 8.0, 0.9);
+        let points = ellipse_points(&expected, 60, 0.0, &fastrand::Rng::with_seed(0));
+        let initial = Ellipse::new(42.0, -8.5, 27.0, 10.0, 1.1);
+        let fitted = fit_ellipse_geometric(&points, &initial).unwrap();
+        let close = (fitted.x - expected.x).abs() < 1e-6
+            && (fitted.y - expected.y).abs() < 1e-6
+            && (fitted.a - expected.a).abs() < 1e-6
+            && (fitted.b - expected.b).abs() < 1e-6
+            && (fitted.theta - expected.theta).abs() < 1e-8;
+        assert!(close, "{:?} != {:?}", fitted, expected);
+        assert!(cost(&points, &fitted) < 1e-12);
+    }
+
+    #[test]
+    fn foot_point_is_the_closest_perimeter_point() {
+        let ellipse = Ellipse::new(5.0, -3.0, 20.0, 6.0, 2.2);
+        let perimeter = (0..100_000)
+            .map(|i| ellipse.point_at(std::f64::consts::TAU * i as f64 / 100_000.0))
+            .collect::<Vec<_>>();
+        let rng = fastrand::Rng::with_seed(5);
+        for _ in 0..50 {
+            let (px, py) = (rng.f64() * 60.0 - 25.0, rng.f64() * 60.0 - 33.0);
+            let foot = ellipse.point_at(foot_point_param(&ellipse, px, py));
+            let distance = (px - foot.x).hypot(py - foot.y);
+            let closest = perimeter
+                .iter()
+                .map(|p| (px - p.x).hypot(py - p.y))
+                .fold(f64::INFINITY, f64::min);
+            assert!(distance <= closest + 1e-6, "({}, {})", px, py);
+        }
+    }
+
+    #[test]
+    fn uncertainty_shrinks_with_more_points() {
+        let ellipse = Ellipse::new(40.0, -10.0, 30.0, 8.0, 0.9);
+        let uncertainty = |n| {
+            let points = ellipse_points(&ellipse, n, 0.5, &fastrand::Rng::with_seed(1));
+            let fitted = fit_ellipse_geometric(&points, &ellipse).unwrap();
+            fit_uncertainty(&points, &fitted).unwrap()
+        };
+        let few = uncertainty(50);
+        let many = uncertainty(800);
+        assert!(many.x < few.x && many.y < few.y);
+        assert!(many.a < few.a && many.b < few.b);
+        assert!(many.theta < few.theta);
+        assert!(fit_uncertainty(
+            &ellipse_points(&ellipse, 5, 0.5, &fastrand::Rng::with_seed(1)),
+            &ellipse
+        )
+        .is_none());
+    }
+}
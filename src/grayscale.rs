@@ -0,0 +1,84 @@
+use image::{ColorType, DynamicImage, Luma};
+use serde::{Deserialize, Serialize};
+
+use crate::binarize::{Gray, GrayValue};
+
+/// Color channel of an RGB image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+/// Value type of the grayscale image the detection runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrayDepth {
+    /// 8-bit for 8-bit images, 16-bit for 16-bit images and floating point for
+    /// floating point images
+    Auto,
+    /// 8-bit values, from 0 to 255
+    #[default]
+    U8,
+    /// 16-bit values, from 0 to 65535
+    U16,
+    /// Floating point values, from 0 to 1
+    F32,
+}
+
+/// Settings of the conversion of the input images to grayscale
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Grayscale {
+    /// If given, this channel is used as the gray value, and the weights are ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+
+    /// Weights of the red, green and blue channels, normalized to a sum of 1.
+    /// If not given, the luma weights of Rec. 709 are used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weights: Option<[f64; 3]>,
+
+    /// Value type of the grayscale image, the threshold is given in the units of this type
+    #[serde(default)]
+    pub depth: GrayDepth,
+}
+
+impl Grayscale {
+    /// The value type used for the given image, `Auto` is resolved from its color type
+    pub fn depth_for(&self, img: &DynamicImage) -> GrayDepth {
+        match self.depth {
+            GrayDepth::Auto => match img.color() {
+                ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+                    GrayDepth::U16
+                }
+                ColorType::Rgb32F | ColorType::Rgba32F => GrayDepth::F32,
+                _ => GrayDepth::U8,
+            },
+            depth => depth,
+        }
+    }
+
+    /// Converts the image to grayscale with the values of the given type
+    pub fn convert<T: GrayValue>(&self, img: &DynamicImage) -> Gray<T> {
+        let weights = match (self.channel, self.weights) {
+            (None, None) => return T::from_image(img),
+            (Some(Channel::Red), _) => [1.0, 0.0, 0.0],
+            (Some(Channel::Green), _) => [0.0, 1.0, 0.0],
+            (Some(Channel::Blue), _) => [0.0, 0.0, 1.0],
+            (None, Some(weights)) => {
+                let sum = weights.iter().sum::<f64>();
+                weights.map(|w| w / sum)
+            }
+        };
+        let weights = weights.map(|w| w as f32);
+        let rgb = img.to_rgb32f();
+        Gray::from_fn(img.width(), img.height(), |x, y| {
+            let p = rgb.get_pixel(x, y);
+            let value = p[0] * weights[0] + p[1] * weights[1] + p[2] * weights[2];
+            Luma([T::from_unit(value)])
+        })
+    }
+}
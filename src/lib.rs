@@ -1,3 +1,19 @@
+pub mod batch;
+pub mod binarize;
+pub mod calibration;
+pub mod detect;
+pub mod draw;
+pub mod error;
+pub mod fit_args;
 pub mod fit_ellipse;
+pub mod frames;
+pub mod geometric_fit;
+pub mod grayscale;
+pub mod output;
 pub mod robust_fit;
-pub mod fit_args;
+pub mod roi;
+pub mod subpixel;
+pub mod tracking;
+
+#[cfg(test)]
+mod test_util;
@@ -1,114 +1,267 @@
-use crate::fit_args::FitArgs;
-use clap::Parser;
-use ellipse_detect::fit_args::CliArgs;
-use image::{io::Reader as ImageReader, Rgba};
-use imageproc::{
-    contours::{find_contours_with_threshold, Contour},
-    drawing::draw_hollow_polygon_mut,
-    point::Point,
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
 };
-use rayon::prelude::*;
-
-use crate::robust_fit::robust_fit_ellipse;
 
-mod fit_args;
-mod fit_ellipse;
-mod robust_fit;
+use clap::Parser;
+use ellipse_detect::{
+    batch::{detect_batch, list_images},
+    detect::{detect_ellipses, load_image, save_image, DetectOptions},
+    draw::draw_ellipses_mut,
+    error::DetectError,
+    fit_args::{env_overrides, CliArgs, FitArgs},
+    frames::{file_frames, numbered_frames, stack_frames},
+    output::{new_writer, write_tracks, OutputFormat},
+    robust_fit::Detection,
+    tracking::Tracker,
+};
+use image::Rgba;
 
 fn main() {
-    let cli_args = CliArgs::parse();
-    let verbosity = cli_args.verbosity;
-    let config_file = std::fs::read_to_string(cli_args.config).unwrap();
-    let fit_args = serde_yaml::from_str::<FitArgs>(&config_file).unwrap();
+    if let Err(e) = run(CliArgs::parse()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    if verbosity > 0 {
-        println!("Opening image with filename {:?}", cli_args.file);
+fn run(cli_args: CliArgs) -> Result<(), DetectError> {
+    let mut overrides = env_overrides()?;
+    overrides.extend(cli_args.overrides.iter().cloned());
+    let mut fit_args = FitArgs::from_file_with_overrides(&cli_args.config, &overrides)?;
+    if cli_args.seed.is_some() {
+        fit_args.seed = cli_args.seed;
+    }
+    if cli_args.print_config {
+        print!("{}", fit_args.to_yaml()?);
+        return Ok(());
     }
-    let img = ImageReader::open(cli_args.file.clone())
-        .expect("Failed to open image")
-        .decode()
-        .expect("Failed to decode image");
-    let img_flat = img.to_luma8();
+    let options = DetectOptions {
+        samplemult: cli_args.samplemult,
+        multithread: cli_args.multithread != 0,
+    };
 
+    match cli_args.file.clone() {
+        Some(file) => run_single(&cli_args, &file, &fit_args, &options),
+        None => run_batch(&cli_args, &fit_args, &options),
+    }
+}
+
+fn run_single(
+    cli_args: &CliArgs,
+    file: &Path,
+    fit_args: &FitArgs,
+    options: &DetectOptions,
+) -> Result<(), DetectError> {
+    let verbosity = cli_args.verbosity;
     if verbosity > 0 {
-        println!("Finding contours in the image...");
-    }
-    let contours: Vec<Vec<Point<f64>>> =
-        find_contours_with_threshold(&img_flat, fit_args.threshold)
-            .into_iter()
-            .filter(|c| {
-                c.points.len() >= fit_args.min_contour_points
-                    && c.points.len() <= fit_args.max_contour_points
-            })
-            .map(|c: Contour<i32>| {
-                let ps = c
-                    .points
-                    .iter()
-                    .copied()
-                    .map(|p: Point<_>| Point::new(p.x.into(), p.y.into()))
-                    .collect::<Vec<_>>();
-                ps
-            })
-            .collect::<Vec<_>>();
-    if verbosity > 0 {
-        println!("Found {} eligible contours!", contours.len());
+        println!("Opening image with filename {:?}", file);
     }
+    let img = load_image(file)?;
 
     if verbosity > 0 {
-        println!("Fitting ellipses to the contours...");
-    }
-    let fit_results = if cli_args.multithread != 0 {
-        contours[..]
-            .par_iter()
-            .map(|ps| robust_fit_ellipse(ps, &fit_args, cli_args.samplemult))
-            .flatten()
-            .collect::<Vec<_>>()
-    } else {
-        contours[..]
-            .iter()
-            .map(|ps| robust_fit_ellipse(ps, &fit_args, cli_args.samplemult))
-            .flatten()
-            .collect::<Vec<_>>()
-    };
+        println!("Finding contours and fitting ellipses...");
+    }
+    let result = detect_ellipses(&img, fit_args, options)?;
+    let fit_results = result.detections;
 
     if verbosity > 0 {
+        println!("Found {} eligible contours!", result.contours.len());
         println!("Found {} ellipses!", fit_results.len());
     }
 
-    if let Some(outfile) = cli_args.outfile {
+    if let Some(outfile) = &cli_args.outfile {
         if verbosity > 0 {
             println!("Writing results to {:?}", outfile);
         }
-        let mut outfile = std::fs::File::create(outfile).expect("Failed to create output file");
-        serde_json::to_writer_pretty(&mut outfile, &fit_results)
-            .expect("Failed to write output file");
+        write_output(
+            outfile,
+            cli_args.format,
+            false,
+            &[(file, None, &fit_results)],
+        )?;
     }
 
-    if let Some(outimg) = cli_args.outimg {
+    if let Some(outimg) = &cli_args.outimg {
         let mut img_with_fits = img.clone();
-        for ellipse in fit_results.iter() {
-            let res = 40;
-            let ellipse_poly = (0..40)
-                .into_iter()
-                .map(|i| {
-                    let angle = (i as f32 / res as f32) * 2.0 * std::f32::consts::PI;
-                    let x = ellipse.a as f32 * angle.cos();
-                    let y = ellipse.b as f32 * angle.sin();
-                    let rotangle = ellipse.theta as f32;
-                    let x_rot = x * rotangle.cos() - y * rotangle.sin();
-                    let y_rot = x * rotangle.sin() + y * rotangle.cos();
-                    Point::new(x_rot + ellipse.x as f32, y_rot + ellipse.y as f32)
-                })
-                .collect::<Vec<Point<f32>>>();
-            draw_hollow_polygon_mut(
-                &mut img_with_fits,
-                &ellipse_poly[..],
-                Rgba([0u8, 0, 255, 255]),
-            );
-        }
+        let ellipses = fit_results.iter().map(|d| d.ellipse).collect::<Vec<_>>();
+        draw_ellipses_mut(&mut img_with_fits, &ellipses, Rgba([0u8, 0, 255, 255]));
         if verbosity > 0 {
             println!("Writing result image to {:?}", outimg);
         }
-        img_with_fits.save(outimg).expect("Failed to save image");
+        save_image(&img_with_fits, outimg)?;
+    }
+
+    Ok(())
+}
+
+fn run_batch(
+    cli_args: &CliArgs,
+    fit_args: &FitArgs,
+    options: &DetectOptions,
+) -> Result<(), DetectError> {
+    let verbosity = cli_args.verbosity;
+    if cli_args.outimg.is_some() {
+        return Err(DetectError::Config {
+            path: None,
+            message: "--outimg is not available in batch mode".to_string(),
+        });
+    }
+    let frames = if let Some(stack) = &cli_args.stack {
+        stack_frames(stack)?
+    } else if let Some(pattern) = &cli_args.pattern {
+        numbered_frames(pattern)?
+    } else {
+        file_frames(list_images(
+            cli_args.input_dir.as_deref(),
+            cli_args.glob.as_deref(),
+        )?)
+    };
+    // An empty batch is most likely a mistyped path, not a run without particles
+    if frames.is_empty() {
+        let message = match (&cli_args.input_dir, &cli_args.glob) {
+            (Some(dir), Some(glob)) => {
+                format!("no images match the glob pattern {:?} in {:?}", glob, dir)
+            }
+            (None, Some(glob)) => format!("no images match the glob pattern {:?}", glob),
+            (Some(dir), None) => format!("no images found in {:?}", dir),
+            (None, None) => "no images found".to_string(),
+        };
+        return Err(DetectError::Config {
+            path: None,
+            message,
+        });
+    }
+    if verbosity > 0 {
+        println!("Processing {} images...", frames.len());
+    }
+    if let Some(outdir) = &cli_args.outdir {
+        // Frames with the same name, e.g. from different directories, would overwrite
+        // each other's output files
+        let mut paths = HashMap::new();
+        for frame in frames.iter() {
+            let path = output_path(outdir, &frame.name(), cli_args.format);
+            if let Some(other) = paths.insert(path.clone(), frame) {
+                return Err(DetectError::Config {
+                    path: None,
+                    message: format!(
+                        "the outputs of {} and {} would both be written to {:?}",
+                        other, frame, path
+                    ),
+                });
+            }
+        }
+        std::fs::create_dir_all(outdir).map_err(|e| DetectError::Io {
+            path: outdir.clone(),
+            source: e,
+        })?;
+    }
+
+    let mut results = detect_batch(&frames, fit_args, options);
+
+    // The frames are in increasing order, failed frames count as frames without ellipses
+    let tracks = match &cli_args.tracks {
+        Some(_) => {
+            let mut tracker = Tracker::new(fit_args.tracking);
+            for res in results.iter_mut() {
+                match &mut res.result {
+                    Ok(detections) => {
+                        let ellipses = detections.iter().map(|d| d.ellipse).collect::<Vec<_>>();
+                        let ids = tracker.update(res.frame.index, &ellipses)?;
+                        for (detection, id) in detections.iter_mut().zip(ids) {
+                            detection.track_id = Some(id);
+                        }
+                    }
+                    Err(_) => {
+                        tracker.update(res.frame.index, &[])?;
+                    }
+                }
+            }
+            Some(tracker.finish())
+        }
+        None => None,
+    };
+
+    let mut succeeded = vec![];
+    let mut failed = 0;
+    for res in results.iter() {
+        match &res.result {
+            Ok(detections) => {
+                println!("{}: {} ellipses", res.frame, detections.len());
+                let frame = Some(res.frame.index);
+                if let Some(outdir) = &cli_args.outdir {
+                    let path = output_path(outdir, &res.frame.name(), cli_args.format);
+                    write_output(
+                        &path,
+                        cli_args.format,
+                        false,
+                        &[(&res.frame.file, frame, detections)],
+                    )?;
+                }
+                succeeded.push((res.frame.file.as_path(), frame, detections));
+            }
+            Err(e) => {
+                println!("{}: FAILED: {}", res.frame, e);
+                failed += 1;
+            }
+        }
+    }
+    let total_ellipses = succeeded.iter().map(|(_, _, d)| d.len()).sum::<usize>();
+    println!(
+        "Processed {} images, found {} ellipses, {} failures",
+        frames.len(),
+        total_ellipses,
+        failed
+    );
+
+    if let Some(outfile) = &cli_args.outfile {
+        if verbosity > 0 {
+            println!("Writing combined results to {:?}", outfile);
+        }
+        write_output(outfile, cli_args.format, true, &succeeded)?;
+    }
+
+    if let (Some(path), Some(tracks)) = (&cli_args.tracks, &tracks) {
+        if verbosity > 0 {
+            println!("Writing {} tracks to {:?}", tracks.len(), path);
+        }
+        let to_error = |e| DetectError::Io {
+            path: path.clone(),
+            source: e,
+        };
+        let file = std::fs::File::create(path).map_err(to_error)?;
+        write_tracks(cli_args.format, std::io::BufWriter::new(file), tracks).map_err(to_error)?;
+    }
+
+    if failed > 0 {
+        return Err(DetectError::BatchFailures {
+            failed,
+            total: frames.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Path of the per-image output file in the output directory
+fn output_path(outdir: &Path, name: &str, format: OutputFormat) -> PathBuf {
+    outdir.join(format!("{}.{}", name, format.extension()))
+}
+
+fn write_output<P: AsRef<Path>, D: AsRef<[Detection]>>(
+    path: &Path,
+    format: OutputFormat,
+    combined: bool,
+    results: &[(P, Option<usize>, D)],
+) -> Result<(), DetectError> {
+    let to_error = |e| DetectError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    };
+    let file = std::fs::File::create(path).map_err(to_error)?;
+    let mut writer = new_writer(format, std::io::BufWriter::new(file), combined);
+    for (image, frame, detections) in results {
+        writer
+            .write_detections(image.as_ref(), *frame, detections.as_ref())
+            .map_err(to_error)?;
     }
+    writer.finish().map_err(to_error)
 }
@@ -0,0 +1,391 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    robust_fit::Detection,
+    tracking::{Track, TrackPoint},
+};
+
+/// File format of the detection results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Pretty printed JSON document
+    #[default]
+    Json,
+    /// Newline delimited JSON, one ellipse per line
+    Jsonl,
+    /// Comma separated values, one ellipse per row
+    Csv,
+}
+
+impl OutputFormat {
+    /// File extension used for this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Csv => "csv",
+        }
+    }
+}
+
+/// Detection results of a single image, as written in combined JSON outputs
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDetections {
+    pub file: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<usize>,
+    pub detections: Vec<Detection>,
+}
+
+/// A single detection along with the name of the image it was found on
+#[derive(Debug, Serialize)]
+struct DetectionRecord<'a> {
+    image: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame: Option<usize>,
+    #[serde(flatten)]
+    detection: &'a Detection,
+}
+
+/// Writes detection results in some output format
+pub trait DetectionWriter {
+    /// Writes the detections found on the given image, `frame` is the index of the image
+    /// in an image sequence
+    fn write_detections(
+        &mut self,
+        image: &Path,
+        frame: Option<usize>,
+        detections: &[Detection],
+    ) -> std::io::Result<()>;
+
+    /// Finishes the output, must be called after the detections of the last image are written
+    fn finish(&mut self) -> std::io::Result<()>;
+}
+
+/// Creates a writer for the given format. If `combined` is false, the JSON output is a plain list
+/// of the detections of a single image, or a single `{file, frame, detections}` object if the
+/// image is a frame of a sequence. Otherwise it lists the detections of each image separately.
+pub fn new_writer<'a, W: Write + 'a>(
+    format: OutputFormat,
+    out: W,
+    combined: bool,
+) -> Box<dyn DetectionWriter + 'a> {
+    match format {
+        OutputFormat::Json => Box::new(JsonWriter {
+            out,
+            combined,
+            files: vec![],
+        }),
+        OutputFormat::Jsonl => Box::new(JsonLinesWriter { out }),
+        OutputFormat::Csv => Box::new(CsvWriter {
+            out,
+            header_written: false,
+        }),
+    }
+}
+
+/// Collects all detections and writes them as a single JSON document when finished
+pub struct JsonWriter<W: Write> {
+    out: W,
+    combined: bool,
+    files: Vec<FileDetections>,
+}
+
+impl<W: Write> DetectionWriter for JsonWriter<W> {
+    fn write_detections(
+        &mut self,
+        image: &Path,
+        frame: Option<usize>,
+        detections: &[Detection],
+    ) -> std::io::Result<()> {
+        self.files.push(FileDetections {
+            file: image.to_path_buf(),
+            frame,
+            detections: detections.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self.files.as_slice() {
+            files if self.combined => serde_json::to_writer_pretty(&mut self.out, files)?,
+            [file] if file.frame.is_some() => serde_json::to_writer_pretty(&mut self.out, file)?,
+            files => {
+                let detections = files
+                    .iter()
+                    .flat_map(|f| f.detections.iter())
+                    .collect::<Vec<_>>();
+                serde_json::to_writer_pretty(&mut self.out, &detections)?;
+            }
+        }
+        self.out.flush()
+    }
+}
+
+/// Writes each detection as a JSON object on its own line
+pub struct JsonLinesWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> DetectionWriter for JsonLinesWriter<W> {
+    fn write_detections(
+        &mut self,
+        image: &Path,
+        frame: Option<usize>,
+        detections: &[Detection],
+    ) -> std::io::Result<()> {
+        for detection in detections {
+            serde_json::to_writer(
+                &mut self.out,
+                &DetectionRecord {
+                    image,
+                    frame,
+                    detection,
+                },
+            )?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes each detection as a CSV row, along with the derived particle dimensions
+pub struct CsvWriter<W: Write> {
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvWriter<W> {
+    const HEADER: &'static str = "image,frame,contour_id,iteration,x,y,a,b,theta,length,width,aspect_ratio,area,fitness,inliers,rms_residual,sigma_x_px,sigma_y_px,sigma_a_px,sigma_b_px,sigma_theta,physical_x,physical_y,physical_a,physical_b,physical_theta,physical_length,physical_width,physical_area,radius,azimuth,radial_orientation,track_id";
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        if !self.header_written {
+            writeln!(self.out, "{}", Self::HEADER)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> DetectionWriter for CsvWriter<W> {
+    fn write_detections(
+        &mut self,
+        image: &Path,
+        frame: Option<usize>,
+        detections: &[Detection],
+    ) -> std::io::Result<()> {
+        self.write_header()?;
+        let image = csv_escape(&image.to_string_lossy());
+        let frame = frame.map(|f| f.to_string()).unwrap_or_default();
+        for d in detections {
+            let e = &d.ellipse;
+            let sigmas = match &d.uncertainty {
+                Some(u) => format!("{},{},{},{},{}", u.x, u.y, u.a, u.b, u.theta),
+                None => ",,,,".to_string(),
+            };
+            let physical = match &d.physical {
+                Some(p) => format!(
+                    "{},{},{},{},{},{},{},{}",
+                    p.x,
+                    p.y,
+                    p.a,
+                    p.b,
+                    p.theta,
+                    p.length(),
+                    p.width(),
+                    p.area()
+                ),
+                None => ",,,,,,,".to_string(),
+            };
+            let polar = match &d.polar {
+                Some(p) => format!("{},{},{}", p.radius, p.azimuth, p.orientation),
+                None => ",,".to_string(),
+            };
+            let track_id = d.track_id.map(|id| id.to_string()).unwrap_or_default();
+            writeln!(
+                self.out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                image,
+                frame,
+                d.contour_id,
+                d.iteration,
+                e.x,
+                e.y,
+                e.a,
+                e.b,
+                e.theta,
+                e.length(),
+                e.width(),
+                e.aspect_ratio(),
+                e.area(),
+                d.fitness,
+                d.inliers,
+                d.rms_residual,
+                sigmas,
+                physical,
+                polar,
+                track_id
+            )?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+/// A point of a track along with the id of the track, as written in newline delimited JSON
+#[derive(Debug, Serialize)]
+struct TrackPointRecord<'a> {
+    track_id: usize,
+    #[serde(flatten)]
+    point: &'a TrackPoint,
+}
+
+/// Writes the trajectories of the tracked particles. The JSON output is a list of the tracks,
+/// while the other formats have one line per point, along with the id of its track.
+pub fn write_tracks<W: Write>(
+    format: OutputFormat,
+    mut out: W,
+    tracks: &[Track],
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Json => serde_json::to_writer_pretty(&mut out, tracks)?,
+        OutputFormat::Jsonl => {
+            for track in tracks {
+                for point in &track.points {
+                    let record = TrackPointRecord {
+                        track_id: track.id,
+                        point,
+                    };
+                    serde_json::to_writer(&mut out, &record)?;
+                    writeln!(out)?;
+                }
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "track_id,frame,x,y,a,b,theta")?;
+            for track in tracks {
+                for p in &track.points {
+                    let e = &p.ellipse;
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{},{}",
+                        track.id, p.frame, e.x, e.y, e.a, e.b, e.theta
+                    )?;
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+/// Quotes the field if it contains characters with special meaning in CSV
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{geometric_fit::Uncertainty, robust_fit::Ellipse};
+
+    /// Splits a CSV row into its fields, keeping the quoted commas
+    fn csv_fields(row: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = row.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(String::new()),
+                c => fields.last_mut().unwrap().push(c),
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn csv_rows_have_a_field_for_each_column() {
+        let ellipse = Ellipse::new(10.0, 20.0, 5.0, 2.0, 0.5);
+        let bare = Detection {
+            ellipse,
+            fitness: 0.9,
+            inliers: 40,
+            rms_residual: 0.3,
+            contour_id: 2,
+            iteration: 1,
+            uncertainty: None,
+            physical: None,
+            polar: None,
+            track_id: None,
+        };
+        let full = Detection {
+            uncertainty: Some(Uncertainty {
+                x: 0.1,
+                y: 0.1,
+                a: 0.2,
+                b: 0.1,
+                theta: 0.01,
+            }),
+            physical: ellipse.scaled(2.0, 2.0),
+            polar: Some(ellipse.polar(0.0, 0.0)),
+            track_id: Some(7),
+            ..bare
+        };
+        let mut out = vec![];
+        {
+            let mut writer = new_writer(OutputFormat::Csv, &mut out, true);
+            writer
+                .write_detections(Path::new("a,b \"c\".png"), Some(3), &[bare, full])
+                .unwrap();
+            writer
+                .write_detections(Path::new("plain.png"), None, &[bare])
+                .unwrap();
+            writer.finish().unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+        let rows = out.lines().map(csv_fields).collect::<Vec<_>>();
+        let columns = CsvWriter::<Vec<u8>>::HEADER.split(',').count();
+        assert_eq!(columns, 33);
+        assert_eq!(rows.len(), 4);
+        for row in rows.iter() {
+            assert_eq!(row.len(), columns, "{:?}", row);
+        }
+        assert_eq!(rows[1][0], "a,b \"c\".png");
+        assert_eq!(rows[1][1], "3");
+        assert!(rows[1][16..].iter().all(|f| f.is_empty()));
+        assert!(rows[2][16..].iter().all(|f| !f.is_empty()));
+        assert_eq!(rows[2][32], "7");
+        assert_eq!(rows[3][1], "");
+    }
+
+    #[test]
+    fn csv_escape_quotes_special_characters() {
+        assert_eq!(csv_escape("plain.png"), "plain.png");
+        assert_eq!(csv_escape("a,b.png"), "\"a,b.png\"");
+        assert_eq!(csv_escape("say \"hi\".png"), "\"say \"\"hi\"\".png\"");
+        assert_eq!(csv_escape("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_escape("carriage\rreturn"), "\"carriage\rreturn\"");
+        assert_eq!(csv_escape(""), "");
+    }
+}
@@ -1,8 +1,13 @@
 use imageproc::point::Point;
 use nalgebra::{Complex, ComplexField};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{fit_args::FitArgs, fit_ellipse::fit_ellipse_dls};
+use crate::{
+    error::DetectError,
+    fit_args::FitArgs,
+    fit_ellipse::{EllipseFitter, FitMethod},
+    geometric_fit::{fit_ellipse_geometric, fit_uncertainty, Uncertainty},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub struct Ellipse {
@@ -17,6 +22,91 @@ pub struct Ellipse {
     axis_b: Point<f64>,
 }
 
+/// An ellipse found by the robust fit, along with the quality metrics of the fit
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Detection {
+    #[serde(flatten)]
+    pub ellipse: Ellipse,
+
+    /// Number of inliers divided by the perimeter of the ellipse
+    pub fitness: f64,
+
+    /// Number of contour points closer to the ellipse than `dist_threshold`
+    pub inliers: usize,
+
+    /// Root mean square distance of the inliers from the ellipse perimeter
+    pub rms_residual: f64,
+
+    /// Index of the contour the ellipse was fitted to
+    pub contour_id: usize,
+
+    /// Index of the RANSAC round in which the ellipse was found on its contour
+    pub iteration: usize,
+
+    /// Standard deviations of the ellipse parameters in pixels, estimated from the inliers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uncertainty: Option<Uncertainty>,
+
+    /// The ellipse in physical units, if a calibration is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub physical: Option<Ellipse>,
+
+    /// Position and orientation relative to the rotation center, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polar: Option<Polar>,
+
+    /// Id of the track of the particle, if the particles are tracked across images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_id: Option<usize>,
+}
+
+/// Position and orientation of an ellipse in polar coordinates around a center
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Polar {
+    /// Distance of the center of the ellipse from the center of rotation
+    pub radius: f64,
+
+    /// Angle of the center of the ellipse around the center of rotation, from the x axis, in [0, 2pi)
+    pub azimuth: f64,
+
+    /// Angle of the major axis from the radial direction, in [-pi/2, pi/2)
+    pub orientation: f64,
+}
+
+/// Settings of the least squares refinement of the best RANSAC hypothesis on its inliers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Refinement {
+    /// Method used for refitting the ellipse on the inliers
+    #[serde(default = "default_refit_fitter")]
+    pub fitter: FitMethod,
+
+    /// Number of refitting rounds, the inliers are selected again before each round
+    #[serde(default = "default_refit_iterations")]
+    pub iterations: usize,
+}
+
+fn default_refit_fitter() -> FitMethod {
+    FitMethod::Geometric
+}
+
+fn default_refit_iterations() -> usize {
+    1
+}
+
+/// Approximation used for calculating the perimeter of an ellipse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PerimeterApprox {
+    /// 2 pi sqrt(a^2 + b^2), which overestimates the perimeter, kept for reproducing old results
+    Legacy,
+    /// Ramanujan's second approximation, relative error below 1e-6 for aspect ratios up to 5
+    #[default]
+    Ramanujan,
+    /// Exact perimeter calculated with the arithmetic-geometric mean
+    Agm,
+}
+
 impl Ellipse {
     /// Creates a new Ellipse struct from the given parameters.
     /// Theta is in radians.
@@ -33,9 +123,204 @@ impl Ellipse {
         }
     }
 
-    /// Returns the perimeter of the ellipse
+    /// Returns the same ellipse in canonical form, where `a` is the semi-major axis,
+    /// `b` is the semi-minor axis and `theta` is the angle of the major axis in [0, pi).
+    pub fn canonical(&self) -> Self {
+        let pi = std::f64::consts::PI;
+        let (a, b, theta) = if self.a >= self.b {
+            (self.a, self.b, self.theta)
+        } else {
+            (self.b, self.a, self.theta + pi / 2.0)
+        };
+        let theta = theta.rem_euclid(pi);
+        let theta = if theta >= pi { 0.0 } else { theta };
+        Ellipse::new(self.x, self.y, a, b, theta)
+    }
+
+    /// Creates an ellipse from the coefficients of the general conic equation
+    /// A x^2 + B xy + C y^2 + D x + E y + F = 0, given in the order [A, B, C, D, E, F].
+    /// The returned ellipse is in canonical form, None is returned if the conic is not a real ellipse.
+    pub fn from_conic(coeffs: [f64; 6]) -> Option<Self> {
+        let [a, b, c, d, e, f] = coeffs;
+        let disc = b * b - 4.0 * a * c;
+        if disc.is_nan() || disc >= 0.0 {
+            return None;
+        }
+        let num = 2.0 * (a * e * e + c * d * d - b * d * e + disc * f);
+        let root = ((a - c).powi(2) + b * b).sqrt();
+        let axis_a = -(num * ((a + c) + root)).sqrt() / disc;
+        let axis_b = -(num * ((a + c) - root)).sqrt() / disc;
+        if !axis_a.is_finite() || !axis_b.is_finite() || axis_a <= 0.0 || axis_b <= 0.0 {
+            return None;
+        }
+        let center_x = (2.0 * c * d - b * e) / disc;
+        let center_y = (2.0 * a * e - b * d) / disc;
+        let theta = 0.5 * (-b).atan2(c - a);
+        Some(Ellipse::new(center_x, center_y, axis_a, axis_b, theta).canonical())
+    }
+
+    /// Returns the coefficients of the general conic equation of the ellipse
+    /// A x^2 + B xy + C y^2 + D x + E y + F = 0, in the order [A, B, C, D, E, F]
+    pub fn to_conic(&self) -> [f64; 6] {
+        let (tsin, tcos) = self.theta.sin_cos();
+        let (a2, b2) = (self.a * self.a, self.b * self.b);
+        let a = a2 * tsin * tsin + b2 * tcos * tcos;
+        let b = 2.0 * (b2 - a2) * tsin * tcos;
+        let c = a2 * tcos * tcos + b2 * tsin * tsin;
+        let d = -2.0 * a * self.x - b * self.y;
+        let e = -b * self.x - 2.0 * c * self.y;
+        let f = a * self.x * self.x + b * self.x * self.y + c * self.y * self.y - a2 * b2;
+        [a, b, c, d, e, f]
+    }
+
+    /// Returns the image of the ellipse when the x coordinates are scaled by `sx` and the y
+    /// coordinates by `sy`, in canonical form. With different scales, the axes and the angle
+    /// change as well, None is returned if the scales are not positive.
+    pub fn scaled(&self, sx: f64, sy: f64) -> Option<Self> {
+        if !(sx > 0.0 && sy > 0.0) {
+            return None;
+        }
+        if sx == sy {
+            let (x, y, a, b) = (self.x * sx, self.y * sx, self.a * sx, self.b * sx);
+            return Some(Ellipse::new(x, y, a, b, self.theta).canonical());
+        }
+        let [a, b, c, d, e, f] = self.to_conic();
+        Ellipse::from_conic([
+            a / (sx * sx),
+            b / (sx * sy),
+            c / (sy * sy),
+            d / sx,
+            e / sy,
+            f,
+        ])
+    }
+
+    /// Position and orientation of the ellipse relative to the center (cx, cy)
+    pub fn polar(&self, cx: f64, cy: f64) -> Polar {
+        let pi = std::f64::consts::PI;
+        let (dx, dy) = (self.x - cx, self.y - cy);
+        let azimuth = dy.atan2(dx).rem_euclid(2.0 * pi);
+        // The axis has no direction, so the angle is taken modulo pi
+        let orientation = (self.theta - azimuth + pi / 2.0).rem_euclid(pi) - pi / 2.0;
+        Polar {
+            radius: dx.hypot(dy),
+            azimuth,
+            orientation,
+        }
+    }
+
+    /// Length of the particle, i.e. the length of the major axis
+    pub fn length(&self) -> f64 {
+        self.a.max(self.b) * 2.0
+    }
+
+    /// Width of the particle, i.e. the length of the minor axis
+    pub fn width(&self) -> f64 {
+        self.a.min(self.b) * 2.0
+    }
+
+    /// Ratio of the length and the width, always >= 1.0
+    pub fn aspect_ratio(&self) -> f64 {
+        self.length() / self.width()
+    }
+
+    /// Area of the ellipse
+    pub fn area(&self) -> f64 {
+        std::f64::consts::PI * self.a * self.b
+    }
+
+    /// Eccentricity of the ellipse, 0 for a circle and approaching 1 for elongated ellipses
+    pub fn eccentricity(&self) -> f64 {
+        let ratio = self.width() / self.length();
+        (1.0 - ratio * ratio).sqrt()
+    }
+
+    /// Axis aligned bounding box of the ellipse, as its top left and bottom right corners
+    pub fn bounding_box(&self) -> (Point<f64>, Point<f64>) {
+        let half_w = ((self.a * self.axis_a.x).powi(2) + (self.b * self.axis_b.x).powi(2)).sqrt();
+        let half_h = ((self.a * self.axis_a.y).powi(2) + (self.b * self.axis_b.y).powi(2)).sqrt();
+        (
+            Point::new(self.x - half_w, self.y - half_h),
+            Point::new(self.x + half_w, self.y + half_h),
+        )
+    }
+
+    /// The two foci of the ellipse, lying on the major axis
+    pub fn foci(&self) -> [Point<f64>; 2] {
+        let c = (self.a * self.a - self.b * self.b).abs().sqrt();
+        let major_axis = if self.a >= self.b {
+            self.axis_a
+        } else {
+            self.axis_b
+        };
+        let center = Point::new(self.x, self.y);
+        let offset = Point::new(major_axis.x * c, major_axis.y * c);
+        [center - offset, center + offset]
+    }
+
+    /// Point of the perimeter at the parameter t (in radians) of the parametric equation
+    pub fn point_at(&self, t: f64) -> Point<f64> {
+        let (tsin, tcos) = t.sin_cos();
+        Point::new(
+            self.x + self.a * tcos * self.axis_a.x + self.b * tsin * self.axis_b.x,
+            self.y + self.a * tcos * self.axis_a.y + self.b * tsin * self.axis_b.y,
+        )
+    }
+
+    /// Returns true if the (px, py) point is inside the ellipse or on its perimeter
+    pub fn contains(&self, px: f64, py: f64) -> bool {
+        let (dx, dy) = (px - self.x, py - self.y);
+        let u = (dx * self.axis_a.x + dy * self.axis_a.y) / self.a;
+        let v = (dx * self.axis_b.x + dy * self.axis_b.y) / self.b;
+        u * u + v * v <= 1.0
+    }
+
+    /// Approximates the perimeter with a polygon of n vertices, evenly spaced in the parameter
+    pub fn to_polygon(&self, n: usize) -> Vec<Point<f64>> {
+        (0..n)
+            .map(|i| self.point_at(i as f64 / n as f64 * 2.0 * std::f64::consts::PI))
+            .collect()
+    }
+
+    /// Returns the perimeter of the ellipse, using Ramanujan's second approximation
     pub fn perimeter(&self) -> f64 {
-        2.0 * std::f64::consts::PI * (self.a * self.a + self.b * self.b).sqrt()
+        self.perimeter_approx(PerimeterApprox::Ramanujan)
+    }
+
+    /// Returns the perimeter of the ellipse, calculated with the given approximation
+    pub fn perimeter_approx(&self, approx: PerimeterApprox) -> f64 {
+        let pi = std::f64::consts::PI;
+        let (a, b) = (
+            self.a.abs().max(self.b.abs()),
+            self.a.abs().min(self.b.abs()),
+        );
+        match approx {
+            PerimeterApprox::Legacy => 2.0 * pi * (a * a + b * b).sqrt(),
+            PerimeterApprox::Ramanujan => {
+                let h = ((a - b) / (a + b)).powi(2);
+                pi * (a + b) * (1.0 + 3.0 * h / (10.0 + (4.0 - 3.0 * h).sqrt()))
+            }
+            PerimeterApprox::Agm => {
+                // Gauss-Kummer type series evaluated with the arithmetic-geometric mean:
+                // P = 2 pi (a^2 - sum 2^(n-1) c_n^2) / AGM(a, b)
+                if b <= 0.0 {
+                    return 4.0 * a;
+                }
+                let (mut an, mut bn) = (a, b);
+                let mut sum = (a * a - b * b) / 2.0;
+                let mut pow = 1.0;
+                for _ in 0..64 {
+                    if an - bn <= f64::EPSILON * an {
+                        break;
+                    }
+                    let cn = (an - bn) / 2.0;
+                    (an, bn) = ((an + bn) / 2.0, (an * bn).sqrt());
+                    sum += pow * cn * cn;
+                    pow *= 2.0;
+                }
+                2.0 * pi * (a * a - sum) / an
+            }
+        }
     }
 
     /// Exact distance of (px, py) point from the ellipse.
@@ -134,6 +419,17 @@ impl Ellipse {
     /// Taken from:
     /// Chatfield, Carl. “Simple Method for Distance to Ellipse.” Wet Robots. Wet Robots, August 28, 2017. https://blog.chatfield.io/simple-method-for-distance-to-ellipse/.
     pub fn distance_from_perimeter_estimate(&self, px_: f64, py_: f64) -> f64 {
+        self.distance_from_perimeter_estimate_with_iterations(px_, py_, 3)
+    }
+
+    /// Distance of (px, py) point from the ellipse, estimated with the given number of
+    /// iterations of the method used by `distance_from_perimeter_estimate`
+    pub fn distance_from_perimeter_estimate_with_iterations(
+        &self,
+        px_: f64,
+        py_: f64,
+        iterations: usize,
+    ) -> f64 {
         let px_ = px_ - self.x;
         let py_ = py_ - self.y;
         let (px_orig, py_orig) = (
@@ -151,7 +447,7 @@ impl Ellipse {
         let mut x = 0.0;
         let mut y = 0.0;
 
-        for _ in 0..3 {
+        for _ in 0..iterations {
             x = a * t.cos();
             y = b * t.sin();
 
@@ -178,84 +474,137 @@ impl Ellipse {
 
 /// Robust ellipse fit on noisy data, based on
 /// Kaewapichai, W. and Kaewtrakulpong, P., 2008. Robust ellipse detection by fitting randomly selected edge patches. World Academy of Science, Engineering, and Technology, 48, pp.30-33.
-pub fn robust_fit_ellipse(cont: &Vec<Point<f64>>, args: &FitArgs, samplemult: f64) -> Vec<Ellipse> {
+/// The random samples are drawn from `rng`, so the result is reproducible for a seeded generator.
+/// The `contour_id` of the returned detections is left at 0, it is up to the caller to set it.
+pub fn robust_fit_ellipse(
+    cont: &[Point<f64>],
+    args: &FitArgs,
+    samplemult: f64,
+    rng: &fastrand::Rng,
+) -> Result<Vec<Detection>, DetectError> {
+    if cont.is_empty() {
+        return Err(DetectError::DegenerateInput(
+            "cannot fit an ellipse to an empty contour".to_string(),
+        ));
+    }
     let mut center_of_mass = cont.iter().fold(Point::new(0.0, 0.0), |acc, p| acc + *p);
     center_of_mass.x /= cont.len() as f64;
     center_of_mass.y /= cont.len() as f64;
+    if !center_of_mass.x.is_finite() || !center_of_mass.y.is_finite() {
+        return Err(DetectError::Numerical(
+            "the center of mass of the contour is not finite".to_string(),
+        ));
+    }
+    if !args.roi.contains(center_of_mass.x, center_of_mass.y) {
+        return Ok(vec![]);
+    }
     center_of_mass.x -= args.rotation_center_x;
     center_of_mass.y -= args.rotation_center_y;
-    if center_of_mass.norm() < args.detect_radius_min
-        || center_of_mass.norm() > args.detect_radius_max
-    {
-        return vec![];
+    let filter_calibration = args.filter_calibration();
+    let radius = match &filter_calibration {
+        Some(calibration) => calibration.length(center_of_mass.x, center_of_mass.y),
+        None => center_of_mass.norm(),
+    };
+    if radius < args.detect_radius_min || radius > args.detect_radius_max {
+        return Ok(vec![]);
     }
 
-    let mut cont = cont.clone();
-    let err: f64 = 0.6;
+    let mut cont = cont.to_vec();
     let d = args.dist_threshold;
-    let pvalue = 1. - err.powf(5.0);
-    let k = ((1. - pvalue).log2() / (1. - (1. - err).powf(5.0)).log2() * samplemult) as usize;
     let min_r = args.radius_threshold;
     let min_fittness = args.min_fitness;
-    let mut best_ellipses: Vec<Ellipse> = vec![];
+    let mut best_ellipses: Vec<Detection> = vec![];
 
     let mut prev_cont_len = 0;
 
-    loop {
-        if cont.len() < 30 || prev_cont_len == cont.len() {
+    let distance = |e: &Ellipse, p: &Point<f64>| {
+        e.distance_from_perimeter_estimate_with_iterations(p.x, p.y, args.distance_iterations)
+    };
+
+    let ellipse_filter = |e: &Ellipse| {
+        if !args.roi.contains(e.x, e.y) {
+            return false;
+        }
+        let e = match &filter_calibration {
+            Some(calibration) => match calibration.to_physical(e) {
+                Some(physical) => physical,
+                None => return false,
+            },
+            None => *e,
+        };
+        let length = e.length();
+        let width = e.width();
+        let pred11 = length >= args.min_length;
+        let pred12 = length <= args.max_length;
+        let pred13 = width >= args.min_width;
+        let pred14 = width <= args.max_width;
+        let aspect = e.aspect_ratio();
+        let pred21 = aspect >= args.min_aspect_ratio;
+        let pred22 = aspect <= args.max_aspect_ratio;
+        pred11 && pred12 && pred13 && pred14 && pred21 && pred22
+    };
+
+    // Only the points of the random pairs are drawn independently, the points added around
+    // them are inliers whenever the pair is
+    let sample_size = 2 * args.sample_pairs;
+
+    for iteration in 0.. {
+        if cont.is_empty() || cont.len() < args.min_remaining_points || prev_cont_len == cont.len()
+        {
             break;
         }
         prev_cont_len = cont.len();
-        let mut samples: Vec<Vec<Point<f64>>> = Vec::with_capacity(k);
-        for _ in 0..k {
+
+        let inlier_count =
+            |e: &Ellipse| cont.iter().filter(|point| distance(e, point) <= d).count();
+        let fitness = |e: &Ellipse| inlier_count(e) as f64 / e.perimeter_approx(args.perimeter);
+
+        let mut sample_count = SampleCount::new(
+            args.confidence,
+            sample_size,
+            samplemult,
+            args.max_iterations,
+        );
+        let mut ellipses: Vec<Ellipse> = vec![];
+        let mut fitnesses: Vec<f64> = vec![];
+        let mut best_hypothesis = f64::NEG_INFINITY;
+        let mut drawn = 0;
+        while !sample_count.is_done(drawn) {
+            drawn += 1;
             let mut sample: Vec<Point<f64>> = vec![];
             let mut added = 0;
             let mut adding_tries = 0;
-            while added < 5 {
+            while added < args.sample_pairs {
                 adding_tries += 1;
-                let p1 = cont.get(fastrand::usize(..cont.len())).unwrap();
-                let p2 = cont.get(fastrand::usize(..cont.len())).unwrap();
-                let distance = (*p1 - *p2).norm();
-                if (distance > min_r * 2.0 && distance < min_r * 10.0) || adding_tries > 10000 {
+                let p1 = cont.get(rng.usize(..cont.len())).unwrap();
+                let p2 = cont.get(rng.usize(..cont.len())).unwrap();
+                let pair_distance = (*p1 - *p2).norm();
+                if (pair_distance > min_r * args.pair_distance_min
+                    && pair_distance < min_r * args.pair_distance_max)
+                    || adding_tries > args.max_pair_tries
+                {
                     sample.extend(
                         cont.iter()
                             .filter(|&p| (*p - *p1).norm() <= min_r || (*p - *p2).norm() <= min_r)
                             .copied(),
                     );
-                    added += 2;
+                    added += 1;
                 }
             }
-            samples.push(sample);
-        }
-
-        let ellipse_filter = |e: &Ellipse| {
-            let length = e.a.max(e.b) * 2.0;
-            let width = e.a.min(e.b) * 2.0;
-            let pred11 = length >= args.min_length;
-            let pred12 = length <= args.max_length;
-            let pred13 = width >= args.min_width;
-            let pred14 = width <= args.max_width;
-            let aspect = length / width;
-            let pred21 = aspect >= args.min_aspect_ratio;
-            let pred22 = aspect <= args.max_aspect_ratio;
-            return pred11 && pred12 && pred13 && pred14 && pred21 && pred22;
-        };
 
-        let ellipses = samples
-            .iter()
-            .filter_map(|s| fit_ellipse_dls(&s[..]))
-            .filter(ellipse_filter)
-            .collect::<Vec<_>>();
-
-        let fitnesses = ellipses
-            .iter()
-            .map(|e| {
-                cont.iter()
-                    .filter(|point| e.distance_from_perimeter_estimate(point.x, point.y) <= d)
-                    .count() as f64
-                    / e.perimeter()
-            })
-            .collect::<Vec<_>>();
+            let ellipse = match args.fitter.fit(&sample[..]) {
+                Ok(e) if ellipse_filter(&e) => e,
+                _ => continue,
+            };
+            let count = inlier_count(&ellipse);
+            let f = count as f64 / ellipse.perimeter_approx(args.perimeter);
+            if f > best_hypothesis {
+                best_hypothesis = f;
+                sample_count.update(count as f64 / cont.len() as f64);
+            }
+            ellipses.push(ellipse);
+            fitnesses.push(f);
+        }
 
         if fitnesses.len() == 0 || !fitnesses.iter().any(|&f| f >= min_fittness) {
             break;
@@ -267,20 +616,140 @@ pub fn robust_fit_ellipse(cont: &Vec<Point<f64>>, args: &FitArgs, samplemult: f6
             .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
             .map(|(index, _)| index)
             .unwrap_or(0);
-        let best_ellipse = ellipses[argmax].clone();
-        best_ellipses.push(best_ellipse);
+        let mut best_ellipse = ellipses[argmax];
+        let mut best_fitness = fitnesses[argmax];
+        if let Some(refinement) = args.refit {
+            let accept = |e: &Ellipse| ellipse_filter(e) && fitness(e) >= min_fittness;
+            best_ellipse = refine_ellipse(&cont, best_ellipse, &refinement, d, distance, accept);
+            best_fitness = fitness(&best_ellipse);
+        }
+        let (inliers, inlier_distances): (Vec<Point<f64>>, Vec<f64>) = cont
+            .iter()
+            .map(|point| (*point, distance(&best_ellipse, point)))
+            .filter(|&(_, r)| r <= d)
+            .unzip();
+        let rms_residual = (inlier_distances.iter().map(|r| r * r).sum::<f64>()
+            / inlier_distances.len().max(1) as f64)
+            .sqrt();
+        best_ellipses.push(Detection {
+            ellipse: best_ellipse,
+            fitness: best_fitness,
+            inliers: inlier_distances.len(),
+            rms_residual,
+            contour_id: 0,
+            iteration,
+            uncertainty: fit_uncertainty(&inliers, &best_ellipse),
+            physical: args
+                .calibration
+                .and_then(|calibration| calibration.to_physical(&best_ellipse)),
+            polar: if args.polar {
+                Some(best_ellipse.polar(args.rotation_center_x, args.rotation_center_y))
+            } else {
+                None
+            },
+            track_id: None,
+        });
 
         cont = cont
             .iter()
-            .filter(|point| {
-                let distance = best_ellipse.distance_from_perimeter_estimate(point.x, point.y);
-                distance >= d
-            })
+            .filter(|point| distance(&best_ellipse, point) >= d)
             .copied()
             .collect();
     }
 
-    best_ellipses
+    Ok(best_ellipses)
+}
+
+/// Expected ratio of inliers on a contour before any hypothesis is evaluated.
+/// With the default settings, this gives about 2900 samples on contours without a good ellipse.
+const INITIAL_INLIER_RATIO: f64 = 0.5;
+
+/// Adaptive number of samples of a RANSAC round. It is estimated from the inlier ratio of the
+/// best hypothesis so far, but never from a lower ratio than the initial guess, and the
+/// sampling stops once it is reached.
+struct SampleCount {
+    confidence: f64,
+    sample_size: usize,
+    samplemult: f64,
+    max_samples: usize,
+    inlier_ratio: f64,
+    required: f64,
+}
+
+impl SampleCount {
+    fn new(confidence: f64, sample_size: usize, samplemult: f64, max_samples: usize) -> Self {
+        SampleCount {
+            confidence,
+            sample_size,
+            samplemult,
+            max_samples,
+            inlier_ratio: INITIAL_INLIER_RATIO,
+            required: required_samples(confidence, INITIAL_INLIER_RATIO, sample_size) * samplemult,
+        }
+    }
+
+    /// Takes the inlier ratio of a new best hypothesis into account
+    fn update(&mut self, inlier_ratio: f64) {
+        if inlier_ratio > self.inlier_ratio {
+            self.inlier_ratio = inlier_ratio;
+            self.required =
+                required_samples(self.confidence, inlier_ratio, self.sample_size) * self.samplemult;
+        }
+    }
+
+    /// True if enough samples are drawn
+    fn is_done(&self, drawn: usize) -> bool {
+        drawn >= self.max_samples || drawn as f64 >= self.required
+    }
+}
+
+/// Number of samples needed to draw at least one sample of `sample_size` inliers with the given
+/// probability, if the ratio of inliers among the contour points is `inlier_ratio`
+fn required_samples(confidence: f64, inlier_ratio: f64, sample_size: usize) -> f64 {
+    let outlier_sample = 1.0 - inlier_ratio.powi(sample_size as i32);
+    if outlier_sample <= 0.0 {
+        return 1.0;
+    }
+    ((1.0 - confidence).ln() / outlier_sample.ln()).max(1.0)
+}
+
+/// Refits the ellipse on the contour points closer to it than `d` (measured by `distance`),
+/// repeated for the configured number of rounds. A refitted ellipse is only kept if it
+/// satisfies `accept`.
+fn refine_ellipse(
+    cont: &[Point<f64>],
+    initial: Ellipse,
+    refinement: &Refinement,
+    d: f64,
+    distance: impl Fn(&Ellipse, &Point<f64>) -> f64,
+    accept: impl Fn(&Ellipse) -> bool,
+) -> Ellipse {
+    let mut current = initial;
+    for _ in 0..refinement.iterations {
+        let inliers = cont
+            .iter()
+            .filter(|point| distance(&current, point) <= d)
+            .copied()
+            .collect::<Vec<_>>();
+        let refit = match refinement.fitter {
+            FitMethod::Geometric => fit_ellipse_geometric(&inliers, &current),
+            fitter => fitter.fit(&inliers),
+        };
+        match refit {
+            Ok(e) if accept(&e) => {
+                let change = (e.x - current.x).abs()
+                    + (e.y - current.y).abs()
+                    + (e.a - current.a).abs()
+                    + (e.b - current.b).abs();
+                current = e;
+                if change < 1e-9 {
+                    break;
+                }
+            }
+            _ => break,
+        }
+    }
+    current
 }
 
 // Implement norm for external Point struct
@@ -293,3 +762,225 @@ impl Norm for Point<f64> {
         (self.x * self.x + self.y * self.y).sqrt()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ellipse_points;
+
+    fn assert_same_ellipse(actual: &Ellipse, expected: &Ellipse) {
+        let pi = std::f64::consts::PI;
+        let angle = (actual.theta - expected.theta).rem_euclid(pi);
+        let close = (actual.x - expected.x).abs() < 1e-9
+            && (actual.y - expected.y).abs() < 1e-9
+            && (actual.a - expected.a).abs() < 1e-9
+            && (actual.b - expected.b).abs() < 1e-9
+            && angle.min(pi - angle) < 1e-9;
+        assert!(close, "{:?} != {:?}", actual, expected);
+    }
+
+    fn test_ellipses() -> Vec<Ellipse> {
+        vec![
+            Ellipse::new(0.0, 0.0, 5.0, 2.0, 0.0),
+            Ellipse::new(10.0, -3.0, 7.5, 1.5, 0.3),
+            Ellipse::new(-4.0, 8.0, 30.0, 12.0, 2.8),
+            Ellipse::new(120.0, 45.0, 3.0, 2.9, -1.2),
+            // The semi-minor axis given first
+            Ellipse::new(1.0, 2.0, 2.0, 6.0, 0.5),
+            Ellipse::new(-50.0, 0.5, 0.8, 4.0, -2.0),
+        ]
+    }
+
+    #[test]
+    fn conic_round_trip() {
+        for ellipse in test_ellipses() {
+            let conic = ellipse.to_conic();
+            let back = Ellipse::from_conic(conic).unwrap();
+            assert_same_ellipse(&back, &ellipse.canonical());
+            assert!(back.a >= back.b);
+            assert!((0.0..std::f64::consts::PI).contains(&back.theta));
+        }
+    }
+
+    #[test]
+    fn conic_is_independent_of_scale_and_sign() {
+        for ellipse in test_ellipses() {
+            let conic = ellipse.to_conic();
+            for factor in [-1.0, -0.01, 3.0, 1e4] {
+                let back = Ellipse::from_conic(conic.map(|c| c * factor)).unwrap();
+                assert_same_ellipse(&back, &ellipse.canonical());
+            }
+        }
+    }
+
+    #[test]
+    fn conic_points_are_on_the_ellipse() {
+        for ellipse in test_ellipses() {
+            let [a, b, c, d, e, f] = ellipse.to_conic();
+            for p in ellipse.to_polygon(16) {
+                let value = a * p.x * p.x + b * p.x * p.y + c * p.y * p.y + d * p.x + e * p.y + f;
+                assert!(value.abs() < 1e-6 * f.abs().max(1.0), "{:?}", ellipse);
+            }
+        }
+    }
+
+    #[test]
+    fn from_conic_rejects_other_conics() {
+        // Hyperbola, parabola, imaginary ellipse and a degenerate point
+        assert!(Ellipse::from_conic([1.0, 0.0, -1.0, 0.0, 0.0, -1.0]).is_none());
+        assert!(Ellipse::from_conic([1.0, 0.0, 0.0, 0.0, -1.0, 0.0]).is_none());
+        assert!(Ellipse::from_conic([1.0, 0.0, 1.0, 0.0, 0.0, 1.0]).is_none());
+        assert!(Ellipse::from_conic([1.0, 0.0, 1.0, 0.0, 0.0, 0.0]).is_none());
+        assert!(Ellipse::from_conic([f64::NAN, 0.0, 1.0, 0.0, 0.0, -1.0]).is_none());
+    }
+
+    #[test]
+    fn scaled_maps_perimeter_points() {
+        for ellipse in test_ellipses() {
+            for (sx, sy) in [(2.0, 2.0), (0.5, 1.5), (3.0, 0.25)] {
+                let scaled = ellipse.scaled(sx, sy).unwrap();
+                assert!(scaled.a >= scaled.b);
+                let [a, b, c, d, e, f] = scaled.to_conic();
+                for p in ellipse.to_polygon(16) {
+                    let (x, y) = (p.x * sx, p.y * sy);
+                    let value = a * x * x + b * x * y + c * y * y + d * x + e * y + f;
+                    assert!(value.abs() < 1e-6 * f.abs().max(1.0), "{:?}", ellipse);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scaled_with_equal_scales_scales_the_axes() {
+        for ellipse in test_ellipses() {
+            let scaled = ellipse.scaled(2.5, 2.5).unwrap();
+            let c = ellipse.canonical();
+            let expected = Ellipse::new(c.x * 2.5, c.y * 2.5, c.a * 2.5, c.b * 2.5, c.theta);
+            assert_same_ellipse(&scaled, &expected);
+        }
+        let ellipse = Ellipse::new(0.0, 0.0, 5.0, 2.0, 0.0);
+        assert!(ellipse.scaled(0.0, 1.0).is_none());
+        assert!(ellipse.scaled(1.0, -2.0).is_none());
+    }
+
+    #[test]
+    fn polar_angles_follow_the_conventions() {
+        let (pi, eps) = (std::f64::consts::PI, 1e-12);
+        let polar = Ellipse::new(13.0, 4.0, 5.0, 2.0, 0.3).polar(3.0, 4.0);
+        assert!((polar.radius - 10.0).abs() < eps);
+        assert!(polar.azimuth.abs() < eps);
+        assert!((polar.orientation - 0.3).abs() < eps);
+
+        // Below the center on the image, i.e. clockwise by pi/2 from the x axis
+        let polar = Ellipse::new(3.0, 14.0, 5.0, 2.0, pi / 2.0 - 0.2).polar(3.0, 4.0);
+        assert!((polar.azimuth - pi / 2.0).abs() < eps);
+        assert!((polar.orientation + 0.2).abs() < eps);
+
+        // Perpendicular to the radius is at the lower end of the range
+        let polar = Ellipse::new(3.0, 14.0, 5.0, 2.0, 0.0).polar(3.0, 4.0);
+        assert!((polar.orientation + pi / 2.0).abs() < eps);
+
+        // Above the center
+        let polar = Ellipse::new(3.0, -6.0, 5.0, 2.0, 0.0).polar(3.0, 4.0);
+        assert!((polar.azimuth - 1.5 * pi).abs() < eps);
+    }
+
+    #[test]
+    fn polar_round_trip() {
+        let pi = std::f64::consts::PI;
+        let (cx, cy) = (50.0, -20.0);
+        for i in 0..24 {
+            for j in 0..12 {
+                let azimuth = i as f64 * pi / 12.0 + 0.01;
+                let ellipse = Ellipse::new(
+                    cx + 30.0 * azimuth.cos(),
+                    cy + 30.0 * azimuth.sin(),
+                    8.0,
+                    3.0,
+                    j as f64 * pi / 12.0,
+                )
+                .canonical();
+                let polar = ellipse.polar(cx, cy);
+                assert!((0.0..2.0 * pi).contains(&polar.azimuth), "{:?}", polar);
+                assert!(
+                    (-pi / 2.0..pi / 2.0).contains(&polar.orientation),
+                    "{:?}",
+                    polar
+                );
+                let back = Ellipse::new(
+                    cx + polar.radius * polar.azimuth.cos(),
+                    cy + polar.radius * polar.azimuth.sin(),
+                    ellipse.a,
+                    ellipse.b,
+                    polar.azimuth + polar.orientation,
+                )
+                .canonical();
+                assert_same_ellipse(&back, &ellipse);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_count_starts_from_the_initial_ratio() {
+        // The default settings: 3 sample pairs, 0.99 confidence and a multiplier of 10
+        let count = SampleCount::new(0.99, 6, 10.0, 10000);
+        assert!(
+            (count.required - 2924.22263).abs() < 1e-4,
+            "{}",
+            count.required
+        );
+        assert!(!count.is_done(2924));
+        assert!(count.is_done(2925));
+        // Lower ratios do not increase the number of samples
+        let mut count = SampleCount::new(0.99, 6, 10.0, 1000);
+        count.update(0.2);
+        assert!(!count.is_done(999));
+        assert!(count.is_done(1000));
+    }
+
+    #[test]
+    fn adaptive_sampling_stops_early_on_clean_contours() {
+        let ellipse = Ellipse::new(40.0, 30.0, 20.0, 8.0, 0.7);
+        let rng = fastrand::Rng::with_seed(11);
+        let contour = ellipse_points(&ellipse, 200, 0.0, &rng);
+        let mut count = SampleCount::new(0.99, 6, 10.0, 10000);
+        let mut best = 0.0;
+        let mut drawn = 0;
+        while !count.is_done(drawn) {
+            drawn += 1;
+            let sample = (0..6)
+                .map(|_| contour[rng.usize(..contour.len())])
+                .collect::<Vec<_>>();
+            let hypothesis = match FitMethod::Dls.fit(&sample) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let inliers = contour
+                .iter()
+                .filter(|p| hypothesis.distance_from_perimeter_estimate(p.x, p.y) <= 1.0)
+                .count();
+            let ratio = inliers as f64 / contour.len() as f64;
+            if ratio > best {
+                best = ratio;
+                count.update(ratio);
+            }
+        }
+        // Every point is an inlier, so a single sample would be enough, times the multiplier
+        assert_eq!(best, 1.0);
+        assert!(drawn <= 20, "{}", drawn);
+    }
+
+    #[test]
+    fn required_samples_matches_hand_computed_values() {
+        let close = |actual: f64, expected: f64| (actual - expected).abs() < 1e-6 * expected;
+        // ln(0.01) / ln(1 - 0.5^6) with the default 3 pairs
+        assert!(close(required_samples(0.99, 0.5, 6), 292.422263));
+        // ln(0.01) / ln(1 - 0.9^2)
+        assert!(close(required_samples(0.99, 0.9, 2), 2.772977));
+        // ln(0.01) / ln(1 - 0.4^10)
+        assert!(close(required_samples(0.99, 0.4, 10), 43916.022665));
+        // Never fewer than one sample, ln(0.5) / ln(1 - 0.9^2) would be 0.417
+        assert_eq!(required_samples(0.5, 0.9, 2), 1.0);
+        assert_eq!(required_samples(0.99, 1.0, 6), 1.0);
+    }
+}
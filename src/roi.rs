@@ -0,0 +1,281 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+
+use crate::{detect::load_image, error::DetectError};
+
+/// A region of the image, in pixel coordinates
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "shape", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Region {
+    /// Axis aligned rectangle, the edges are part of the region
+    Rectangle {
+        x_min: f64,
+        y_min: f64,
+        x_max: f64,
+        y_max: f64,
+    },
+    /// Circle given by its center and radius, the perimeter is part of the region
+    Circle { x: f64, y: f64, radius: f64 },
+    /// Polygon given by its vertices, the inside is determined by the even-odd rule
+    Polygon { points: Vec<[f64; 2]> },
+}
+
+impl Region {
+    /// Checks if the point (x, y) is inside the region
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        match self {
+            Region::Rectangle {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => x >= *x_min && x <= *x_max && y >= *y_min && y <= *y_max,
+            Region::Circle {
+                x: cx,
+                y: cy,
+                radius,
+            } => (x - cx).hypot(y - cy) <= *radius,
+            Region::Polygon { points } => {
+                // Counts the crossings of the edges with a horizontal ray from the point
+                let mut inside = false;
+                for (i, p) in points.iter().enumerate() {
+                    let q = points[(i + 1) % points.len()];
+                    if (p[1] > y) != (q[1] > y) {
+                        let crossing_x = p[0] + (y - p[1]) / (q[1] - p[1]) * (q[0] - p[0]);
+                        if x < crossing_x {
+                            inside = !inside;
+                        }
+                    }
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Region of interest, the contours and ellipses are only kept if their centers are inside.
+/// A point is inside if it is in any of the `include` regions (or there are none),
+/// it is not in any of the `exclude` regions and it is on a non-zero pixel of the mask.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Roi {
+    /// Regions to keep, the whole image is kept if empty
+    #[serde(default)]
+    pub include: Vec<Region>,
+
+    /// Regions to throw away
+    #[serde(default)]
+    pub exclude: Vec<Region>,
+
+    /// Pathname of a binary mask image, relative paths are resolved from the directory of the config file
+    #[serde(default)]
+    pub mask: Option<PathBuf>,
+
+    /// The mask image, loaded by `load_mask`. The detection fails if `mask` is given
+    /// but this is not loaded.
+    #[serde(skip)]
+    pub mask_image: Option<Arc<GrayImage>>,
+}
+
+impl Roi {
+    /// Loads the mask image, if one is given. Relative paths are resolved from `base_dir`.
+    pub fn load_mask(&mut self, base_dir: Option<&Path>) -> Result<(), DetectError> {
+        if let Some(mask) = &self.mask {
+            let path = match base_dir {
+                Some(dir) if mask.is_relative() => dir.join(mask),
+                _ => mask.clone(),
+            };
+            self.mask_image = Some(Arc::new(load_image(path)?.to_luma8()));
+        }
+        Ok(())
+    }
+
+    /// False if a mask is given, but its image has not been loaded with `load_mask`
+    pub fn is_loaded(&self) -> bool {
+        self.mask.is_none() || self.mask_image.is_some()
+    }
+
+    /// Checks that the mask image, if one is loaded, has the size of the image
+    pub fn check_size(&self, width: u32, height: u32) -> Result<(), DetectError> {
+        match &self.mask_image {
+            Some(mask) if mask.dimensions() != (width, height) => Err(DetectError::config(
+                None,
+                format!(
+                    "the roi.mask image {:?} is {}x{} pixels, but the image is {}x{}",
+                    self.mask.as_deref().unwrap_or_else(|| Path::new("")),
+                    mask.width(),
+                    mask.height(),
+                    width,
+                    height
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks if the point (x, y) is inside the region of interest.
+    /// Points outside of the mask image are not inside.
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|r| r.contains(x, y));
+        let excluded = self.exclude.iter().any(|r| r.contains(x, y));
+        let masked = match &self.mask_image {
+            Some(mask) => {
+                let (px, py) = (x.round(), y.round());
+                px < 0.0
+                    || py < 0.0
+                    || px >= mask.width() as f64
+                    || py >= mask.height() as f64
+                    || mask.get_pixel(px as u32, py as u32)[0] == 0
+            }
+            None => false,
+        };
+        included && !excluded && !masked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(x_min: f64, y_min: f64, x_max: f64, y_max: f64) -> Region {
+        Region::Rectangle {
+            x_min,
+            y_min,
+            x_max,
+            y_max,
+        }
+    }
+
+    #[test]
+    fn polygon_uses_the_even_odd_rule() {
+        // A U shape, open at the top between x = 4 and x = 6
+        let u = Region::Polygon {
+            points: vec![
+                [0.0, 0.0],
+                [4.0, 0.0],
+                [4.0, 6.0],
+                [6.0, 6.0],
+                [6.0, 0.0],
+                [10.0, 0.0],
+                [10.0, 10.0],
+                [0.0, 10.0],
+            ],
+        };
+        assert!(u.contains(2.0, 3.0));
+        assert!(u.contains(8.0, 3.0));
+        assert!(u.contains(5.0, 8.0));
+        assert!(!u.contains(5.0, 3.0));
+        assert!(!u.contains(-1.0, 3.0));
+        assert!(!u.contains(11.0, 3.0));
+        assert!(!u.contains(5.0, 11.0));
+
+        // A self-intersecting pentagram, its center is crossed twice and it is outside
+        let star = Region::Polygon {
+            points: (0..5)
+                .map(|i| {
+                    let t = (i * 2) as f64 * std::f64::consts::TAU / 5.0;
+                    [10.0 * t.sin(), -10.0 * t.cos()]
+                })
+                .collect(),
+        };
+        assert!(!star.contains(0.0, 0.0));
+        assert!(star.contains(0.0, -8.0));
+    }
+
+    #[test]
+    fn boundary_pixels_are_inside() {
+        let r = rectangle(2.0, 3.0, 8.0, 5.0);
+        for (x, y) in [(2.0, 3.0), (8.0, 5.0), (5.0, 3.0), (2.0, 4.0)] {
+            assert!(r.contains(x, y), "{} {}", x, y);
+        }
+        for (x, y) in [(1.0, 4.0), (9.0, 4.0), (5.0, 2.0), (5.0, 6.0)] {
+            assert!(!r.contains(x, y), "{} {}", x, y);
+        }
+
+        let c = Region::Circle {
+            x: 10.0,
+            y: 20.0,
+            radius: 5.0,
+        };
+        for (x, y) in [(15.0, 20.0), (5.0, 20.0), (10.0, 25.0), (13.0, 24.0)] {
+            assert!(c.contains(x, y), "{} {}", x, y);
+        }
+        for (x, y) in [(16.0, 20.0), (10.0, 14.0), (14.0, 24.0)] {
+            assert!(!c.contains(x, y), "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn exclude_takes_precedence_over_include() {
+        let roi = Roi {
+            include: vec![
+                rectangle(0.0, 0.0, 10.0, 10.0),
+                rectangle(20.0, 0.0, 30.0, 10.0),
+            ],
+            exclude: vec![rectangle(5.0, 5.0, 25.0, 10.0)],
+            ..Default::default()
+        };
+        assert!(roi.contains(2.0, 2.0));
+        assert!(roi.contains(28.0, 2.0));
+        assert!(!roi.contains(15.0, 2.0));
+        assert!(!roi.contains(7.0, 7.0));
+        assert!(!roi.contains(22.0, 7.0));
+
+        // Without include regions, everything but the excluded regions is inside
+        let roi = Roi {
+            exclude: vec![rectangle(5.0, 5.0, 25.0, 10.0)],
+            ..Default::default()
+        };
+        assert!(roi.contains(-100.0, 2.0));
+        assert!(!roi.contains(7.0, 7.0));
+    }
+
+    #[test]
+    fn mask_lookups() {
+        let mask = GrayImage::from_fn(4, 3, |x, _| image::Luma([if x < 2 { 0 } else { 255 }]));
+        let roi = Roi {
+            mask: Some(PathBuf::from("mask.png")),
+            mask_image: Some(Arc::new(mask)),
+            ..Default::default()
+        };
+        assert!(roi.contains(2.0, 0.0));
+        assert!(roi.contains(3.4, 2.4));
+        // Rounded to the nearest pixel
+        assert!(roi.contains(1.6, 1.0));
+        assert!(!roi.contains(1.4, 1.0));
+        assert!(!roi.contains(0.0, 0.0));
+        for (x, y) in [
+            (-1.0, 1.0),
+            (3.0, -1.0),
+            (3.6, 1.0),
+            (3.0, 2.6),
+            (100.0, 100.0),
+        ] {
+            assert!(!roi.contains(x, y), "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn mask_must_have_the_size_of_the_image() {
+        let roi = Roi {
+            mask: Some(PathBuf::from("mask.png")),
+            mask_image: Some(Arc::new(GrayImage::new(4, 3))),
+            ..Default::default()
+        };
+        assert!(roi.check_size(4, 3).is_ok());
+        for (width, height) in [(3, 4), (4, 4), (5, 3)] {
+            match roi.check_size(width, height) {
+                Err(DetectError::Config { message, .. }) => {
+                    assert!(message.contains("mask.png"), "{}", message)
+                }
+                other => panic!("expected a config error, got {:?}", other),
+            }
+        }
+        assert!(Roi::default().check_size(10, 10).is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+use image::{ImageBuffer, Luma};
+use imageproc::point::Point;
+
+use crate::binarize::{Gray, GrayValue};
+
+type GradientImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Refines integer contour pixels to sub-pixel edge positions using the image gradient.
+/// Each point is moved along the gradient direction to the maximum of the gradient magnitude,
+/// which is located by fitting a parabola to the magnitudes sampled along that direction.
+pub struct EdgeRefiner {
+    gx: GradientImage,
+    gy: GradientImage,
+    magnitude: GradientImage,
+    min_magnitude: f32,
+}
+
+impl EdgeRefiner {
+    /// Search range along the gradient direction, in pixels
+    const SEARCH_RADIUS: i32 = 2;
+
+    /// Gradient magnitudes below this value are considered flat, and the point is kept as is.
+    /// Given for 8-bit images, scaled to the value range of other image types.
+    const MIN_MAGNITUDE: f32 = 1.0;
+
+    /// Calculates the gradients of the image used for refining the edge points
+    pub fn new<T: GrayValue>(img: &Gray<T>) -> Self {
+        let gx = sobel(img, [[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]]);
+        let gy = sobel(img, [[-1.0, -2.0, -1.0], [0.0, 0.0, 0.0], [1.0, 2.0, 1.0]]);
+        let min_magnitude = Self::MIN_MAGNITUDE * (T::WHITE / u8::MAX as f64) as f32;
+        let magnitude = GradientImage::from_fn(img.width(), img.height(), |x, y| {
+            Luma([gx.get_pixel(x, y)[0].hypot(gy.get_pixel(x, y)[0])])
+        });
+        EdgeRefiner {
+            gx,
+            gy,
+            magnitude,
+            min_magnitude,
+        }
+    }
+
+    /// Returns the refined positions of the given contour points
+    pub fn refine(&self, contour: &[Point<f64>]) -> Vec<Point<f64>> {
+        contour.iter().map(|&p| self.refine_point(p)).collect()
+    }
+
+    fn refine_point(&self, p: Point<f64>) -> Point<f64> {
+        let gx = sample(&self.gx, p.x, p.y);
+        let gy = sample(&self.gy, p.x, p.y);
+        let norm = gx.hypot(gy);
+        if norm < self.min_magnitude {
+            return p;
+        }
+        let (nx, ny) = ((gx / norm) as f64, (gy / norm) as f64);
+        let magnitude_at = |s: f64| sample(&self.magnitude, p.x + s * nx, p.y + s * ny);
+
+        let (best_step, best_mag) = (-Self::SEARCH_RADIUS..=Self::SEARCH_RADIUS)
+            .map(|s| (s, magnitude_at(s as f64)))
+            .fold((0, f32::NEG_INFINITY), |best, curr| {
+                if curr.1 > best.1 {
+                    curr
+                } else {
+                    best
+                }
+            });
+        if best_step.abs() == Self::SEARCH_RADIUS {
+            return p;
+        }
+
+        let prev = magnitude_at(best_step as f64 - 1.0);
+        let next = magnitude_at(best_step as f64 + 1.0);
+        let denom = prev - 2.0 * best_mag + next;
+        let delta = if denom < 0.0 {
+            (0.5 * (prev - next) / denom).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+        let shift = best_step as f64 + delta as f64;
+        Point::new(p.x + shift * nx, p.y + shift * ny)
+    }
+}
+
+/// Bilinear interpolation of the image at the given position, clamped to the image borders
+fn sample(img: &GradientImage, x: f64, y: f64) -> f32 {
+    let max_x = img.width() as f64 - 1.0;
+    let max_y = img.height() as f64 - 1.0;
+    let x = x.clamp(0.0, max_x);
+    let y = y.clamp(0.0, max_y);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (x1, y1) = ((x0 + 1.0).min(max_x), (y0 + 1.0).min(max_y));
+    let (fx, fy) = ((x - x0) as f32, (y - y0) as f32);
+    let at = |x: f64, y: f64| img.get_pixel(x as u32, y as u32)[0];
+    let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+    let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// Correlates the image with the 3x3 Sobel kernel, the pixels beyond the border take the
+/// value of the nearest border pixel
+fn sobel<T: GrayValue>(img: &Gray<T>, kernel: [[f32; 3]; 3]) -> GradientImage {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return GradientImage::new(width, height);
+    }
+    let at = |x: i64, y: i64| -> f32 {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        img.get_pixel(x, y)[0].into() as f32
+    };
+    GradientImage::from_fn(width, height, |x, y| {
+        let mut sum = 0.0;
+        for (ky, row) in kernel.iter().enumerate() {
+            for (kx, w) in row.iter().enumerate() {
+                sum += w * at(x as i64 + kx as i64 - 1, y as i64 + ky as i64 - 1);
+            }
+        }
+        Luma([sum])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fit_ellipse::fit_ellipse_dls, robust_fit::Ellipse};
+    use image::GrayImage;
+    use imageproc::contours::find_contours;
+
+    /// Dark ellipse on a bright background, the border pixels are shaded by their coverage
+    fn render(e: &Ellipse) -> GrayImage {
+        const SUBSAMPLES: u32 = 8;
+        let (sin, cos) = e.theta.sin_cos();
+        GrayImage::from_fn(100, 80, |x, y| {
+            let mut covered = 0;
+            for i in 0..SUBSAMPLES * SUBSAMPLES {
+                let sx = x as f64 + ((i % SUBSAMPLES) as f64 + 0.5) / SUBSAMPLES as f64 - 0.5;
+                let sy = y as f64 + ((i / SUBSAMPLES) as f64 + 0.5) / SUBSAMPLES as f64 - 0.5;
+                let (dx, dy) = (sx - e.x, sy - e.y);
+                let (u, v) = (dx * cos + dy * sin, -dx * sin + dy * cos);
+                if (u / e.a).powi(2) + (v / e.b).powi(2) <= 1.0 {
+                    covered += 1;
+                }
+            }
+            let coverage = covered as f64 / (SUBSAMPLES * SUBSAMPLES) as f64;
+            Luma([(200.0 - 150.0 * coverage).round() as u8])
+        })
+    }
+
+    #[test]
+    fn refined_points_fit_better() {
+        let ellipse = Ellipse::new(47.3, 38.6, 30.0, 18.0, 0.5);
+        let img = render(&ellipse);
+        let particle = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+            Luma([if img.get_pixel(x, y)[0] < 125 { 255 } else { 0 }])
+        });
+        let contours = find_contours::<i32>(&particle);
+        assert_eq!(contours.len(), 1);
+        let points = contours[0]
+            .points
+            .iter()
+            .map(|p| Point::new(p.x as f64, p.y as f64))
+            .collect::<Vec<_>>();
+        let refined = EdgeRefiner::new(&img).refine(&points);
+        assert_eq!(refined.len(), points.len());
+
+        let errors = |points: &[Point<f64>]| {
+            let fit = fit_ellipse_dls(points).unwrap();
+            (
+                (fit.x - ellipse.x).hypot(fit.y - ellipse.y),
+                (fit.a - ellipse.a).abs().max((fit.b - ellipse.b).abs()),
+            )
+        };
+        let (center_error, axis_error) = errors(&points);
+        let (refined_center_error, refined_axis_error) = errors(&refined);
+        assert!(
+            refined_center_error < center_error,
+            "{} {}",
+            refined_center_error,
+            center_error
+        );
+        assert!(
+            refined_axis_error < axis_error,
+            "{} {}",
+            refined_axis_error,
+            axis_error
+        );
+        assert!(refined_axis_error < 0.1, "{}", refined_axis_error);
+    }
+
+    #[test]
+    fn points_in_flat_regions_are_kept() {
+        let img = render(&Ellipse::new(47.3, 38.6, 30.0, 18.0, 0.5));
+        let refiner = EdgeRefiner::new(&img);
+        let points = [
+            Point::new(3.0, 4.0),
+            Point::new(95.0, 75.0),
+            Point::new(47.0, 38.0),
+            Point::new(50.25, 40.5),
+        ];
+        assert_eq!(refiner.refine(&points), points.to_vec());
+    }
+
+    #[test]
+    fn empty_images_have_empty_gradients() {
+        for (width, height) in [(0, 0), (0, 5), (5, 0)] {
+            let refiner = EdgeRefiner::new(&Gray::<u8>::new(width, height));
+            assert_eq!(refiner.magnitude.dimensions(), (width, height));
+            assert!(refiner.refine(&[]).is_empty());
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use imageproc::point::Point;
+
+use crate::robust_fit::Ellipse;
+
+/// `n` points of the ellipse, evenly spaced in the parameter between `start` and `end`
+/// (in radians), each coordinate moved by uniform noise of the given amplitude
+pub fn ellipse_arc_points(
+    e: &Ellipse,
+    n: usize,
+    (start, end): (f64, f64),
+    noise: f64,
+    rng: &fastrand::Rng,
+) -> Vec<Point<f64>> {
+    (0..n)
+        .map(|i| {
+            let p = e.point_at(start + (end - start) * i as f64 / n as f64);
+            Point::new(
+                p.x + noise * (2.0 * rng.f64() - 1.0),
+                p.y + noise * (2.0 * rng.f64() - 1.0),
+            )
+        })
+        .collect()
+}
+
+/// `n` points evenly spaced along the whole ellipse, moved by uniform noise of the given amplitude
+pub fn ellipse_points(e: &Ellipse, n: usize, noise: f64, rng: &fastrand::Rng) -> Vec<Point<f64>> {
+    ellipse_arc_points(e, n, (0.0, std::f64::consts::TAU), noise, rng)
+}
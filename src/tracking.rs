@@ -0,0 +1,482 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{error::DetectError, robust_fit::Ellipse};
+
+/// Settings of the association of the ellipses between consecutive frames
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackingOptions {
+    /// Maximum distance of an ellipse from the predicted center of a track to be associated with it, in pixels
+    #[serde(default = "default_max_distance")]
+    pub max_distance: f64,
+
+    /// Weight of the differences of the semi-axes in the association cost
+    #[serde(default = "default_shape_weight")]
+    pub shape_weight: f64,
+
+    /// Weight of the difference of the orientations (in radians) in the association cost
+    #[serde(default = "default_angle_weight")]
+    pub angle_weight: f64,
+
+    /// Number of consecutive frames a track can miss before it is ended
+    #[serde(default = "default_max_missed")]
+    pub max_missed: usize,
+}
+
+fn default_max_distance() -> f64 {
+    20.0
+}
+
+fn default_shape_weight() -> f64 {
+    1.0
+}
+
+fn default_angle_weight() -> f64 {
+    10.0
+}
+
+fn default_max_missed() -> usize {
+    2
+}
+
+impl Default for TrackingOptions {
+    fn default() -> Self {
+        TrackingOptions {
+            max_distance: default_max_distance(),
+            shape_weight: default_shape_weight(),
+            angle_weight: default_angle_weight(),
+            max_missed: default_max_missed(),
+        }
+    }
+}
+
+/// An ellipse of a track, along with the index of the frame it was found on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TrackPoint {
+    pub frame: usize,
+    #[serde(flatten)]
+    pub ellipse: Ellipse,
+}
+
+/// Trajectory of a particle, the ellipses associated with each other in consecutive frames
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Track {
+    pub id: usize,
+    pub points: Vec<TrackPoint>,
+}
+
+impl Track {
+    /// Predicted center of the particle in the given frame, assuming constant velocity
+    fn predict(&self, frame: usize) -> (f64, f64) {
+        let last = &self.points[self.points.len() - 1];
+        let (x, y) = (last.ellipse.x, last.ellipse.y);
+        if self.points.len() < 2 {
+            return (x, y);
+        }
+        let prev = &self.points[self.points.len() - 2];
+        let steps = (frame - last.frame) as f64 / (last.frame - prev.frame) as f64;
+        (
+            x + (x - prev.ellipse.x) * steps,
+            y + (y - prev.ellipse.y) * steps,
+        )
+    }
+}
+
+/// Associates the ellipses of consecutive frames with each other, using the Hungarian method
+/// on the differences of the centers and shapes. Ellipses that are not associated with any
+/// track start a new one, and tracks that are not continued for more than `max_missed` frames
+/// are ended.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    options: TrackingOptions,
+    /// Tracks that can still be continued, along with the number of frames they missed
+    active: Vec<(Track, usize)>,
+    finished: Vec<Track>,
+    next_id: usize,
+    /// Index of the last frame given to `update`
+    last_frame: Option<usize>,
+}
+
+/// Cost of the pairs outside of the gate, larger than any real cost
+const GATED_COST: f64 = 1e12;
+
+impl Tracker {
+    pub fn new(options: TrackingOptions) -> Self {
+        Tracker {
+            options,
+            active: vec![],
+            finished: vec![],
+            next_id: 0,
+            last_frame: None,
+        }
+    }
+
+    /// Associates the ellipses found on the given frame with the tracks.
+    /// The frames must be given in strictly increasing order, an error is returned otherwise.
+    /// Every frame counts as a missed frame for the tracks that are not continued on it,
+    /// so frames without any ellipses (or that failed) should be given with no ellipses.
+    /// Returns the track id of each ellipse.
+    pub fn update(
+        &mut self,
+        frame: usize,
+        ellipses: &[Ellipse],
+    ) -> Result<Vec<usize>, DetectError> {
+        if let Some(last_frame) = self.last_frame {
+            if frame <= last_frame {
+                return Err(DetectError::DegenerateInput(format!(
+                    "frame {} is given to the tracker after frame {}",
+                    frame, last_frame
+                )));
+            }
+        }
+        self.last_frame = Some(frame);
+
+        let cost = self
+            .active
+            .iter()
+            .map(|(track, _)| {
+                ellipses
+                    .iter()
+                    .map(|e| self.cost(track, frame, e).unwrap_or(GATED_COST))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let assignment = hungarian(&cost);
+
+        let mut ids = vec![None; ellipses.len()];
+        for (t, (track, missed)) in self.active.iter_mut().enumerate() {
+            match assignment[t] {
+                Some(i) if cost[t][i] < GATED_COST => {
+                    track.points.push(TrackPoint {
+                        frame,
+                        ellipse: ellipses[i],
+                    });
+                    *missed = 0;
+                    ids[i] = Some(track.id);
+                }
+                _ => *missed += 1,
+            }
+        }
+
+        let max_missed = self.options.max_missed;
+        let (ended, active): (Vec<_>, Vec<_>) = self
+            .active
+            .drain(..)
+            .partition(|(_, missed)| *missed > max_missed);
+        self.active = active;
+        self.finished
+            .extend(ended.into_iter().map(|(track, _)| track));
+
+        let ids = ids
+            .iter()
+            .zip(ellipses)
+            .map(|(id, ellipse)| match id {
+                Some(id) => *id,
+                None => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    self.active.push((
+                        Track {
+                            id,
+                            points: vec![TrackPoint {
+                                frame,
+                                ellipse: *ellipse,
+                            }],
+                        },
+                        0,
+                    ));
+                    id
+                }
+            })
+            .collect();
+        Ok(ids)
+    }
+
+    /// Ends all tracks and returns them, ordered by their ids
+    pub fn finish(mut self) -> Vec<Track> {
+        self.finished
+            .extend(self.active.drain(..).map(|(track, _)| track));
+        self.finished.sort_by_key(|track| track.id);
+        self.finished
+    }
+
+    /// Cost of continuing the track with the ellipse, None if it is too far from the prediction
+    fn cost(&self, track: &Track, frame: usize, e: &Ellipse) -> Option<f64> {
+        let (px, py) = track.predict(frame);
+        let distance = (e.x - px).hypot(e.y - py);
+        if distance.is_nan() || distance > self.options.max_distance {
+            return None;
+        }
+        let last = &track.points[track.points.len() - 1].ellipse;
+        let shape = (e.a - last.a).abs() + (e.b - last.b).abs();
+        Some(
+            distance
+                + self.options.shape_weight * shape
+                + self.options.angle_weight * angle_difference(e.theta, last.theta),
+        )
+    }
+}
+
+/// Difference of two axis orientations, which are equivalent modulo pi, in [0, pi/2]
+fn angle_difference(a: f64, b: f64) -> f64 {
+    let pi = std::f64::consts::PI;
+    let diff = (a - b).rem_euclid(pi);
+    diff.min(pi - diff)
+}
+
+/// Solves the assignment problem for the given cost matrix with the Hungarian method,
+/// returns the column assigned to each row. If there are more rows than columns,
+/// some of the rows are left unassigned.
+/// Uses the O(n^2 m) shortest augmenting path formulation with row and column potentials.
+/// Based on:
+/// Kuhn, H.W., 1955. The Hungarian method for the assignment problem. Naval Research Logistics Quarterly, 2(1-2), pp.83-97.
+fn hungarian(cost: &[Vec<f64>]) -> Vec<Option<usize>> {
+    let n = cost.len();
+    let m = cost.first().map(|row| row.len()).unwrap_or(0);
+    if n == 0 || m == 0 {
+        return vec![None; n];
+    }
+    if n > m {
+        let transposed = (0..m)
+            .map(|j| (0..n).map(|i| cost[i][j]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut assignment = vec![None; n];
+        for (j, i) in hungarian(&transposed).into_iter().enumerate() {
+            if let Some(i) = i {
+                assignment[i] = Some(j);
+            }
+        }
+        return assignment;
+    }
+
+    // Row and column potentials, and the row matched to each column, 1-based with 0 as a sentinel
+    let mut u = vec![0.0; n + 1];
+    let mut v = vec![0.0; m + 1];
+    let mut matched = vec![0; m + 1];
+    let mut way = vec![0; m + 1];
+    for i in 1..=n {
+        matched[0] = i;
+        let mut j0 = 0;
+        let mut min_v = vec![f64::INFINITY; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[j0] = true;
+            let i0 = matched[j0];
+            let mut delta = f64::INFINITY;
+            let mut j1 = 0;
+            for j in 1..=m {
+                if !used[j] {
+                    let reduced = cost[i0 - 1][j - 1] - u[i0] - v[j];
+                    if reduced < min_v[j] {
+                        min_v[j] = reduced;
+                        way[j] = j0;
+                    }
+                    if min_v[j] < delta {
+                        delta = min_v[j];
+                        j1 = j;
+                    }
+                }
+            }
+            for j in 0..=m {
+                if used[j] {
+                    u[matched[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_v[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if matched[j0] == 0 {
+                break;
+            }
+        }
+        // Augments the matching along the alternating path
+        loop {
+            let j1 = way[j0];
+            matched[j0] = matched[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![None; n];
+    for j in 1..=m {
+        if matched[j] != 0 {
+            assignment[matched[j] - 1] = Some(j - 1);
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smallest total cost of assigning min(n, m) rows to distinct columns
+    fn brute_force(cost: &[Vec<f64>]) -> f64 {
+        fn search(cost: &[Vec<f64>], row: usize, used: &mut Vec<bool>, left: usize) -> f64 {
+            if left == 0 {
+                return 0.0;
+            }
+            if cost.len() - row < left {
+                return f64::INFINITY;
+            }
+            // The row is either left unassigned or assigned to one of the free columns
+            let mut best = search(cost, row + 1, used, left);
+            for j in 0..used.len() {
+                if !used[j] {
+                    used[j] = true;
+                    best = best.min(cost[row][j] + search(cost, row + 1, used, left - 1));
+                    used[j] = false;
+                }
+            }
+            best
+        }
+        let m = cost[0].len();
+        search(cost, 0, &mut vec![false; m], cost.len().min(m))
+    }
+
+    #[test]
+    fn hungarian_matches_brute_force() {
+        let rng = fastrand::Rng::with_seed(7);
+        for n in 1..=5 {
+            for m in 1..=5 {
+                for _ in 0..20 {
+                    let cost = (0..n)
+                        .map(|_| (0..m).map(|_| rng.f64() * 10.0).collect())
+                        .collect::<Vec<Vec<f64>>>();
+                    let assignment = hungarian(&cost);
+                    assert_eq!(assignment.len(), n);
+                    let columns = assignment.iter().flatten().collect::<Vec<_>>();
+                    assert_eq!(columns.len(), n.min(m));
+                    let mut unique = columns.clone();
+                    unique.sort();
+                    unique.dedup();
+                    assert_eq!(unique.len(), columns.len());
+
+                    let total = assignment
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, j)| j.map(|j| cost[i][j]))
+                        .sum::<f64>();
+                    let expected = brute_force(&cost);
+                    assert!(
+                        (total - expected).abs() < 1e-9,
+                        "{:?}: {} != {}",
+                        cost,
+                        total,
+                        expected
+                    );
+                }
+            }
+        }
+    }
+
+    fn particle(x: f64, y: f64) -> Ellipse {
+        Ellipse::new(x, y, 10.0, 3.0, 0.5)
+    }
+
+    #[test]
+    fn unmatched_ellipses_start_new_tracks() {
+        let mut tracker = Tracker::new(TrackingOptions::default());
+        let ids = tracker.update(0, &[particle(10.0, 10.0), particle(100.0, 10.0)]);
+        assert_eq!(ids.unwrap(), vec![0, 1]);
+        let frame = [
+            particle(200.0, 10.0),
+            particle(102.0, 11.0),
+            particle(12.0, 9.0),
+        ];
+        assert_eq!(tracker.update(1, &frame).unwrap(), vec![2, 1, 0]);
+        let tracks = tracker.finish();
+        let lengths = tracks.iter().map(|t| t.points.len()).collect::<Vec<_>>();
+        assert_eq!(lengths, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn ellipses_outside_the_gate_are_not_associated() {
+        let mut tracker = Tracker::new(TrackingOptions::default());
+        tracker.update(0, &[particle(10.0, 10.0)]).unwrap();
+        // The particle moved farther than max_distance
+        assert_eq!(tracker.update(1, &[particle(35.0, 10.0)]).unwrap(), vec![1]);
+        // A different shape is associated if it is close enough
+        let other_shape = Ellipse::new(36.0, 10.0, 12.0, 3.5, 0.6);
+        assert_eq!(tracker.update(2, &[other_shape]).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn tracks_end_after_max_missed_frames() {
+        let mut tracker = Tracker::new(TrackingOptions::default());
+        tracker.update(0, &[particle(10.0, 10.0)]).unwrap();
+        // Missing max_missed frames continues the track
+        tracker.update(1, &[]).unwrap();
+        tracker.update(2, &[]).unwrap();
+        assert_eq!(tracker.update(3, &[particle(10.0, 10.0)]).unwrap(), vec![0]);
+        // Missing more frames ends it
+        for frame in 4..7 {
+            tracker.update(frame, &[]).unwrap();
+        }
+        assert_eq!(tracker.update(7, &[particle(10.0, 10.0)]).unwrap(), vec![1]);
+        let tracks = tracker.finish();
+        let frames = tracks
+            .iter()
+            .map(|t| t.points.iter().map(|p| p.frame).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(frames, vec![vec![0, 3], vec![7]]);
+    }
+
+    #[test]
+    fn ids_are_stable_across_frames() {
+        let mut tracker = Tracker::new(TrackingOptions::default());
+        for frame in 0..10 {
+            let t = frame as f64;
+            let mut ellipses = vec![
+                particle(15.0 * t, 20.0),
+                particle(300.0, 15.0 * t),
+                particle(100.0 + 5.0 * t, 100.0 - 5.0 * t),
+            ];
+            let mut expected = vec![0, 1, 2];
+            if frame % 2 == 1 {
+                ellipses.reverse();
+                expected.reverse();
+            }
+            assert_eq!(tracker.update(frame, &ellipses).unwrap(), expected);
+        }
+        let tracks = tracker.finish();
+        assert_eq!(tracks.len(), 3);
+        assert!(tracks.iter().all(|t| t.points.len() == 10));
+    }
+
+    #[test]
+    fn crossing_particles_keep_their_tracks() {
+        let mut tracker = Tracker::new(TrackingOptions::default());
+        for frame in 0..11 {
+            let t = frame as f64;
+            let ellipses = [
+                particle(10.0 * t, 50.0),
+                Ellipse::new(100.0 - 10.0 * t, 52.0, 10.0, 3.0, 2.0),
+            ];
+            assert_eq!(tracker.update(frame, &ellipses).unwrap(), vec![0, 1]);
+        }
+        let tracks = tracker.finish();
+        assert!(tracks[0].points.iter().all(|p| p.ellipse.y == 50.0));
+        assert!(tracks[1].points.iter().all(|p| p.ellipse.y == 52.0));
+    }
+
+    #[test]
+    fn frames_must_increase() {
+        let mut tracker = Tracker::new(TrackingOptions::default());
+        tracker.update(0, &[particle(10.0, 10.0)]).unwrap();
+        tracker.update(2, &[particle(12.0, 10.0)]).unwrap();
+        assert!(tracker.update(1, &[particle(11.0, 10.0)]).is_err());
+        assert!(tracker.update(2, &[particle(12.0, 10.0)]).is_err());
+        assert_eq!(tracker.update(3, &[particle(13.0, 10.0)]).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn hungarian_handles_empty_matrices() {
+        assert_eq!(hungarian(&[]), vec![]);
+        assert_eq!(hungarian(&[vec![], vec![]]), vec![None, None]);
+    }
+}